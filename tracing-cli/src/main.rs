@@ -0,0 +1,776 @@
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::process;
+
+use tracing::analysis::CascadeStats;
+use tracing::audit::AuditTrail;
+use tracing::canon::Canonicalizer;
+use tracing::encrypted::{self, EncryptedStore};
+use tracing::keyring::Keyring;
+use tracing::media::{self, MediaHasher};
+use tracing::retention::{self, Holds};
+use tracing::snapshot::{self, Header, Summary};
+use tracing::store::{check_namespace, Namespaced};
+use tracing::{path, tree, Participant};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scheme {
+    Path,
+    Tree,
+}
+
+impl Scheme {
+    fn from_args(args: &ArgMatches) -> Scheme {
+        match args.value_of("scheme") {
+            Some("tree") => Scheme::Tree,
+            _ => Scheme::Path,
+        }
+    }
+
+    fn route(self, server: &str, endpoint: &str) -> String {
+        match self {
+            Scheme::Path => format!("{}/{}", server, endpoint),
+            Scheme::Tree => format!("{}/tree/{}", server, endpoint),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct TreeTraceResponse {
+    #[serde(flatten)]
+    outcome: tree::TraceOutcome,
+    stats: Option<CascadeStats>,
+}
+
+fn read_json<T: DeserializeOwned>(file: &str) -> Result<T, String> {
+    let f = File::open(file).map_err(|e| format!("{}: {}", file, e))?;
+    serde_json::from_reader(f).map_err(|e| format!("{}: {}", file, e))
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string(value).unwrap());
+}
+
+fn post(
+    url: &str,
+    query: &[(&str, &str)],
+    body: serde_json::Value,
+) -> Result<ureq::Response, String> {
+    let mut req = ureq::post(url);
+    for (param, value) in query {
+        req.query(param, value);
+    }
+    let resp = req.send_json(body);
+    if let Some(e) = resp.synthetic_error() {
+        return Err(format!("{}: {}", url, e));
+    }
+    if !resp.ok() {
+        return Err(format!(
+            "{}: server returned {} {}",
+            url,
+            resp.status(),
+            resp.into_string().unwrap_or_default()
+        ));
+    }
+    Ok(resp)
+}
+
+fn keygen() -> Result<(), String> {
+    print_json(&rand::random::<[u8; 16]>());
+    Ok(())
+}
+
+fn keyring_generate(args: &ArgMatches) -> Result<(), String> {
+    let file = args.value_of("keyring").unwrap();
+    if std::path::Path::new(file).exists() {
+        return Err(format!("{}: already exists", file));
+    }
+    Keyring::generate()
+        .save(file)
+        .map_err(|e| format!("{}: {}", file, e))
+}
+
+// The running server picks up the rotated keyring without a restart.
+fn keyring_rotate(args: &ArgMatches) -> Result<(), String> {
+    let file = args.value_of("keyring").unwrap();
+    let version = Keyring::edit(file, |k| k.rotate()).map_err(|e| format!("{}: {}", file, e))?;
+    println!("current key: {}", version);
+    Ok(())
+}
+
+fn keyring_list(args: &ArgMatches) -> Result<(), String> {
+    let file = args.value_of("keyring").unwrap();
+    let keyring = Keyring::load(file).map_err(|e| format!("{}: {}", file, e))?;
+    for (version, key) in keyring.versions() {
+        match key.retired_at {
+            Some(t) => println!("{}\tcreated {}\tretired {}", version, key.created_at, t),
+            None => println!("{}\tcreated {}\tcurrent", version, key.created_at),
+        }
+    }
+    Ok(())
+}
+
+fn connect(args: &ArgMatches) -> Result<redis::Connection, String> {
+    let url = args.value_of("redis").unwrap();
+    redis::Client::open(url)
+        .and_then(|client| client.get_connection())
+        .map_err(|e| format!("{}: {}", url, e))
+}
+
+fn print_summary(summary: &Summary) {
+    println!(
+        "namespace {}\tscheme {}\tsuite {}",
+        summary.header.namespace, summary.header.scheme, summary.header.suite
+    );
+    println!(
+        "{} records\tsha3-256 {}",
+        summary.records,
+        summary.digest_hex()
+    );
+}
+
+// Reads straight from the server's Redis database rather than through the server.
+fn snapshot_export(args: &ArgMatches) -> Result<(), String> {
+    let conn = connect(args)?;
+    let file = args.value_of("out").unwrap();
+    let header = Header::new(
+        args.value_of("namespace").unwrap(),
+        args.value_of("snapshot-scheme").unwrap(),
+        args.value_of("suite").unwrap(),
+    );
+    check_namespace(&header.namespace).map_err(|e| e.to_string())?;
+    let mut w = BufWriter::new(File::create(file).map_err(|e| format!("{}: {}", file, e))?);
+    let summary =
+        snapshot::export(&conn, &header, &mut w).map_err(|e| format!("{}: {}", file, e))?;
+    print_summary(&summary);
+    Ok(())
+}
+
+// The whole file is verified before anything is written to the database.
+fn snapshot_import(args: &ArgMatches) -> Result<(), String> {
+    let file = args.value_of("in").unwrap();
+    let r = File::open(file)
+        .map(BufReader::new)
+        .map_err(|e| format!("{}: {}", file, e))?;
+    let summary = if args.is_present("verify-only") {
+        snapshot::verify(r)
+    } else {
+        snapshot::import(&connect(args)?, args.value_of("suite").unwrap(), r)
+    };
+    print_summary(&summary.map_err(|e| format!("{}: {}", file, e))?);
+    Ok(())
+}
+
+// The plaintext given with `--message`, canonicalized with the default settings if asked, or the
+// commitment message of the attachment given with `--media`, hashed as it is read.
+fn read_message(args: &ArgMatches) -> Result<Vec<u8>, String> {
+    let file = match args.value_of("media") {
+        Some(file) => file,
+        None => {
+            let m = args.value_of("message").unwrap().as_bytes();
+            return Ok(if args.is_present("canonicalize") {
+                Canonicalizer::default().canonicalize(m)
+            } else {
+                m.to_vec()
+            });
+        }
+    };
+    let mut r = File::open(file).map_err(|e| format!("{}: {}", file, e))?;
+    let mut hasher = MediaHasher::new();
+    let mut buf = vec![0; media::CHUNK_SIZE];
+    loop {
+        match r.read(&mut buf).map_err(|e| format!("{}: {}", file, e))? {
+            0 => break,
+            n => hasher.update(&buf[..n]),
+        }
+    }
+    Ok(hasher.finalize().commitment().message())
+}
+
+fn new_message(args: &ArgMatches) -> Result<(), String> {
+    let m = &read_message(args)?[..];
+    match Scheme::from_args(args) {
+        Scheme::Path => print_json(&path::new_message(m)),
+        Scheme::Tree => print_json(&tree::new_message(m)),
+    };
+    Ok(())
+}
+
+fn tag(args: &ArgMatches) -> Result<(), String> {
+    let k: [u8; 16] = read_json(args.value_of("key").unwrap())?;
+    let m = &read_message(args)?[..];
+    let md = args.value_of("metadata").unwrap();
+    match Scheme::from_args(args) {
+        Scheme::Path => {
+            let md: path::TraceMetadata = read_json(md)?;
+            print_json(&path::generate_tag(&k, m, &md));
+        }
+        Scheme::Tree => {
+            let md: tree::TraceMetadata = read_json(md)?;
+            let ctr = args
+                .value_of("ctr")
+                .unwrap()
+                .parse::<u32>()
+                .map_err(|e| format!("ctr: {}", e))?;
+            print_json(&tree::generate_tag(&k, m, &md, ctr));
+        }
+    };
+    Ok(())
+}
+
+fn process(args: &ArgMatches) -> Result<(), String> {
+    let scheme = Scheme::from_args(args);
+    let stag: serde_json::Value = read_json(args.value_of("tag").unwrap())?;
+    let sid = parse_participant(args, "sid", "sid-device")?;
+    let rid = parse_participant(args, "rid", "rid-device")?;
+    let url = scheme.route(args.value_of("server").unwrap(), "process");
+    let body = serde_json::json!({ "stag": stag, "sid": sid, "rid": rid });
+    let rtag: serde_json::Value = post(&url, &[], body)?
+        .into_json()
+        .map_err(|e| e.to_string())?;
+    print_json(&rtag);
+    Ok(())
+}
+
+fn verify(args: &ArgMatches) -> Result<(), String> {
+    let k: [u8; 16] = read_json(args.value_of("key").unwrap())?;
+    let m = &read_message(args)?[..];
+    let rtag = args.value_of("tag").unwrap();
+    let verified = match Scheme::from_args(args) {
+        Scheme::Path => {
+            let rtag: path::RecTraceTag = read_json(rtag)?;
+            path::verify_tag(&k, m, &rtag).map(|md| print_json(&md))
+        }
+        Scheme::Tree => {
+            let rtag: tree::RecTraceTag = read_json(rtag)?;
+            tree::verify_tag_wellformed(&k, m, &rtag).map(|md| print_json(&md))
+        }
+    };
+    verified.ok_or_else(|| "Receiver tag does not verify".to_string())
+}
+
+fn post_trace(args: &ArgMatches, query: &[(&str, &str)]) -> Result<ureq::Response, String> {
+    let scheme = Scheme::from_args(args);
+    let tmd: serde_json::Value = read_json(args.value_of("metadata").unwrap())?;
+    let uid = parse_participant(args, "uid", "uid-device")?;
+    let url = scheme.route(args.value_of("server").unwrap(), "trace");
+    let body = serde_json::json!({
+        "m": read_message(args)?,
+        "tmd": tmd,
+        "uid": uid,
+    });
+    post(&url, query, body)
+}
+
+fn trace(args: &ArgMatches) -> Result<(), String> {
+    let scheme = Scheme::from_args(args);
+    let mut query = Vec::new();
+    if let Some(format) = args.value_of("format") {
+        query.push(("format", format));
+    }
+    if args.is_present("stats") {
+        query.push(("stats", "true"));
+    }
+    let resp = post_trace(args, &query)?;
+    if args.is_present("format") {
+        print!("{}", resp.into_string().map_err(|e| e.to_string())?);
+        return Ok(());
+    }
+
+    match scheme {
+        Scheme::Path => {
+            let outcome: path::TraceOutcome =
+                resp.into_json_deserialize().map_err(|e| e.to_string())?;
+            let hops: Vec<String> = outcome.path.iter().map(|uid| uid.to_string()).collect();
+            println!("{}", hops.join(" <- "));
+            print_stop(outcome.stop, outcome.hop, outcome.origin_confirmed);
+        }
+        Scheme::Tree => {
+            let resp: TreeTraceResponse =
+                resp.into_json_deserialize().map_err(|e| e.to_string())?;
+            let outcome = resp.outcome;
+            print_tree(&outcome.tree, "");
+            print_stop(outcome.stop, outcome.hop, outcome.origin_confirmed);
+            if let Some(stats) = resp.stats {
+                println!("recipients: {}", stats.recipients);
+                println!("depth: {}", stats.depth);
+                println!("max fan-out: {}", stats.max_fanout);
+                println!("structural virality: {:.3}", stats.structural_virality);
+                println!("forwarders: {}", stats.forwarders);
+                println!("level counts: {:?}", stats.level_counts);
+            }
+        }
+    };
+    Ok(())
+}
+
+// Traces through the server and holds the records the trace read.
+fn hold(args: &ArgMatches) -> Result<(), String> {
+    let file = args.value_of("holds").unwrap();
+    let mut holds = Holds::load(file).map_err(|e| format!("{}: {}", file, e))?;
+    let audit = AuditTrail::new(args.value_of("audit").unwrap());
+    let case = args.value_of("case").unwrap();
+    let reason = args.value_of("reason").unwrap();
+    let resp = post_trace(args, &[])?;
+    let held = match Scheme::from_args(args) {
+        Scheme::Path => {
+            let outcome: path::TraceOutcome =
+                resp.into_json_deserialize().map_err(|e| e.to_string())?;
+            holds.place(case, reason, &outcome, file, &audit)
+        }
+        Scheme::Tree => {
+            let resp: TreeTraceResponse =
+                resp.into_json_deserialize().map_err(|e| e.to_string())?;
+            holds.place(case, reason, &resp.outcome, file, &audit)
+        }
+    };
+    let held = held.map_err(|e| format!("{}: {}", case, e))?;
+    println!("{}: holding {} records", case, held);
+    Ok(())
+}
+
+fn hold_release(args: &ArgMatches) -> Result<(), String> {
+    let file = args.value_of("holds").unwrap();
+    let mut holds = Holds::load(file).map_err(|e| format!("{}: {}", file, e))?;
+    let audit = AuditTrail::new(args.value_of("audit").unwrap());
+    let case = args.value_of("case").unwrap();
+    let released = holds
+        .release(case, file, &audit)
+        .map_err(|e| format!("{}: {}", case, e))?;
+    println!("{}: released {} records", case, released);
+    Ok(())
+}
+
+fn hold_list(args: &ArgMatches) -> Result<(), String> {
+    let file = args.value_of("holds").unwrap();
+    let holds = Holds::load(file).map_err(|e| format!("{}: {}", file, e))?;
+    for (case, hold) in holds.cases() {
+        println!(
+            "{}\tcreated {}\t{} records\t{}",
+            case,
+            hold.created_at,
+            hold.addrs.len(),
+            hold.reason
+        );
+    }
+    Ok(())
+}
+
+// Reads the records through the keyring to learn when each was processed, and then removes the keys
+// retired for as long as the retention period that no remaining record was written under.
+fn sweep(args: &ArgMatches) -> Result<(), String> {
+    let conn = connect(args)?;
+    let keyring_file = args.value_of("keyring").unwrap();
+    let keyring = Keyring::load(keyring_file).map_err(|e| format!("{}: {}", keyring_file, e))?;
+    let file = args.value_of("holds").unwrap();
+    let holds = Holds::load(file).map_err(|e| format!("{}: {}", file, e))?;
+    let audit = AuditTrail::new(args.value_of("audit").unwrap());
+    let retention = args.value_of("retention").unwrap();
+    let retention = retention
+        .parse::<u64>()
+        .map_err(|e| format!("retention: {}", e))?;
+    let store =
+        Namespaced::new(&conn, args.value_of("namespace").unwrap()).map_err(|e| e.to_string())?;
+    let swept = retention::sweep(
+        &EncryptedStore::new(&store, &keyring),
+        &holds,
+        retention,
+        &audit,
+    )
+    .map_err(|e| e.to_string())?;
+    println!(
+        "removed {} records, {} of them without a key; kept {} held",
+        swept.removed, swept.keyless, swept.held
+    );
+    if swept.tampered > 0 {
+        println!("kept {} records that fail to open", swept.tampered);
+    }
+
+    let in_use = encrypted::key_versions(&store).map_err(|e| e.to_string())?;
+    // Reloads the keyring under its lock, so a key rotated in since the sweep started is kept
+    let pruned = Keyring::edit(keyring_file, |k| k.prune_unused(retention, &in_use))
+        .map_err(|e| format!("{}: {}", keyring_file, e))?;
+    for v in pruned {
+        println!("removed key: {}", v);
+    }
+    Ok(())
+}
+
+fn reseal(args: &ArgMatches) -> Result<(), String> {
+    let conn = connect(args)?;
+    let file = args.value_of("keyring").unwrap();
+    let keyring = Keyring::load(file).map_err(|e| format!("{}: {}", file, e))?;
+    let namespace =
+        Namespaced::new(&conn, args.value_of("namespace").unwrap()).map_err(|e| e.to_string())?;
+    let resealed = encrypted::reseal(&namespace, &keyring).map_err(|e| e.to_string())?;
+    println!("resealed {} records", resealed);
+    Ok(())
+}
+
+fn print_stop(stop: tracing::StopReason, hop: usize, origin_confirmed: bool) {
+    let root = if origin_confirmed {
+        "confirmed origin"
+    } else {
+        "furthest reachable sender"
+    };
+    println!("stopped: {:?} after {} hop(s); root is {}", stop, hop, root);
+}
+
+fn print_tree(tree: &tree::TraceTree, indent: &str) {
+    if indent.is_empty() {
+        println!("{}", tree.uid);
+    }
+    for (i, edge) in tree.edges.iter().enumerate() {
+        let last = i + 1 == tree.edges.len() && !tree.tampered;
+        let mut notes = Vec::new();
+        if let Some(ctr) = edge.ctr {
+            notes.push(format!("ctr {}", ctr));
+        }
+        if let Some(ts) = edge.processed_at {
+            notes.push(format!("at {}", ts));
+        }
+        if !edge.wellformed {
+            notes.push("malformed".to_string());
+        }
+        println!(
+            "{}{} {}  [{}]",
+            indent,
+            if last { "`--" } else { "|--" },
+            edge.child.uid,
+            notes.join(", ")
+        );
+        let child_indent = format!("{}{}", indent, if last { "    " } else { "|   " });
+        print_tree(&edge.child, &child_indent);
+    }
+    if tree.tampered {
+        println!("{}`-- ?  [tampered record]", indent);
+    }
+}
+
+fn parse_participant(args: &ArgMatches, name: &str, device: &str) -> Result<Participant, String> {
+    let user = args.value_of(name).unwrap();
+    if user.is_empty() {
+        return Err(format!("{}: empty user id", name));
+    }
+    let device = args.value_of(device).unwrap_or("").as_bytes();
+    Ok(Participant::new(user, device))
+}
+
+fn main() {
+    let scheme = Arg::with_name("scheme")
+        .long("scheme")
+        .takes_value(true)
+        .possible_values(&["path", "tree"])
+        .default_value("path")
+        .help("Traceback scheme");
+    let server = Arg::with_name("server")
+        .long("server")
+        .takes_value(true)
+        .default_value("http://localhost:8000")
+        .help("Base URL of tracing-server");
+    let message = Arg::with_name("message")
+        .long("message")
+        .short("m")
+        .takes_value(true)
+        .required_unless("media")
+        .help("Message plaintext");
+    let media = Arg::with_name("media")
+        .long("media")
+        .takes_value(true)
+        .conflicts_with("message")
+        .help("Attachment file, traced by its media commitment instead of a plaintext");
+    let canonicalize = Arg::with_name("canonicalize")
+        .long("canonicalize")
+        .conflicts_with("media")
+        .help("Canonicalize the plaintext before using it");
+    let key = Arg::with_name("key")
+        .long("key")
+        .short("k")
+        .takes_value(true)
+        .required(true)
+        .help("JSON file holding the message key");
+    let metadata = Arg::with_name("metadata")
+        .long("metadata")
+        .takes_value(true)
+        .required(true)
+        .help("JSON file holding the trace metadata");
+    let keyring = Arg::with_name("keyring")
+        .long("keyring")
+        .takes_value(true)
+        .default_value("keyring.json")
+        .help("JSON file holding the server keyring");
+    let namespace = Arg::with_name("namespace")
+        .long("namespace")
+        .takes_value(true)
+        .default_value("")
+        .help("Namespace the server stores its records in, empty for the whole database");
+    let suite = Arg::with_name("suite")
+        .long("suite")
+        .takes_value(true)
+        .possible_values(&[snapshot::SUITE, snapshot::SUITE_ENCRYPTED])
+        .default_value(snapshot::SUITE_ENCRYPTED)
+        .help("Cryptographic suite of the stored records");
+    let holds = Arg::with_name("holds")
+        .long("holds")
+        .takes_value(true)
+        .default_value("holds.json")
+        .help("JSON file holding the legal holds");
+    let audit = Arg::with_name("audit")
+        .long("audit")
+        .takes_value(true)
+        .default_value("audit.log")
+        .help("Audit trail file to append to");
+    let case = Arg::with_name("case")
+        .long("case")
+        .takes_value(true)
+        .required(true)
+        .help("Name of the investigation the hold belongs to");
+    let redis = Arg::with_name("redis")
+        .long("redis")
+        .takes_value(true)
+        .default_value("redis://127.0.0.1:6379/")
+        .help("URL of the server's Redis database");
+    let uid = |name: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .takes_value(true)
+            .required(true)
+            .help(help)
+    };
+    let device = |name: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .takes_value(true)
+            .help("Device of the user, when the user has several")
+    };
+
+    let matches = App::new("tracing-cli")
+        .about("Client and moderator operations against tracing-server")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(SubCommand::with_name("keygen").about("Generate a random message key"))
+        .subcommand(
+            SubCommand::with_name("keyring-generate")
+                .about("Create a server keyring with a fresh key")
+                .arg(keyring.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("keyring-rotate")
+                .about("Add a new current key to a server keyring")
+                .arg(keyring.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("keyring-list")
+                .about("List the key versions in a server keyring")
+                .arg(keyring.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot-export")
+                .about("Write every stored record to a snapshot file")
+                .arg(redis.clone())
+                .arg(
+                    Arg::with_name("out")
+                        .long("out")
+                        .short("o")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Snapshot file to write"),
+                )
+                .arg(namespace.clone())
+                .arg(
+                    Arg::with_name("snapshot-scheme")
+                        .long("scheme")
+                        .takes_value(true)
+                        .possible_values(&["path", "tree", "mixed"])
+                        .default_value("mixed")
+                        .help("Traceback scheme of the stored records"),
+                )
+                .arg(suite.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot-import")
+                .about("Verify a snapshot file and store its records in its namespace")
+                .arg(redis.clone())
+                .arg(suite)
+                .arg(
+                    Arg::with_name("in")
+                        .long("in")
+                        .short("i")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Snapshot file to read"),
+                )
+                .arg(
+                    Arg::with_name("verify-only")
+                        .long("verify-only")
+                        .help("Only check the record count and digest"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("new-message")
+                .about("Generate trace metadata for a new message")
+                .arg(scheme.clone())
+                .arg(message.clone())
+                .arg(media.clone())
+                .arg(canonicalize.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("tag")
+                .about("Create a sender trace tag")
+                .arg(scheme.clone())
+                .arg(key.clone())
+                .arg(message.clone())
+                .arg(media.clone())
+                .arg(canonicalize.clone())
+                .arg(metadata.clone())
+                .arg(
+                    Arg::with_name("ctr")
+                        .long("ctr")
+                        .takes_value(true)
+                        .default_value("0")
+                        .help("Generator key counter (tree scheme)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("process")
+                .about("Submit a sender trace tag to the server")
+                .arg(scheme.clone())
+                .arg(server.clone())
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .takes_value(true)
+                        .required(true)
+                        .help("JSON file holding the sender trace tag"),
+                )
+                .arg(uid("sid", "Sender id"))
+                .arg(device("sid-device"))
+                .arg(uid("rid", "Recipient id"))
+                .arg(device("rid-device")),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Verify a receiver trace tag and output the new trace metadata")
+                .arg(scheme.clone())
+                .arg(key)
+                .arg(message.clone())
+                .arg(media.clone())
+                .arg(canonicalize.clone())
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .takes_value(true)
+                        .required(true)
+                        .help("JSON file holding the receiver trace tag"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("trace")
+                .about("Trace a reported message")
+                .arg(scheme.clone())
+                .arg(server.clone())
+                .arg(message.clone())
+                .arg(media.clone())
+                .arg(canonicalize.clone())
+                .arg(metadata.clone())
+                .arg(uid("uid", "Reporting user id"))
+                .arg(device("uid-device"))
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["json", "dot", "newick"])
+                        .help("Print the trace exported in this format"),
+                )
+                .arg(
+                    Arg::with_name("stats")
+                        .long("stats")
+                        .conflicts_with("format")
+                        .help("Include cascade statistics (tree scheme)"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("hold")
+                .about("Trace a reported message and hold the records it read")
+                .arg(scheme)
+                .arg(server)
+                .arg(message)
+                .arg(media)
+                .arg(canonicalize)
+                .arg(metadata)
+                .arg(uid("uid", "Reporting user id"))
+                .arg(device("uid-device"))
+                .arg(case.clone())
+                .arg(
+                    Arg::with_name("reason")
+                        .long("reason")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Why the records are held, for the audit trail"),
+                )
+                .arg(holds.clone())
+                .arg(audit.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("hold-release")
+                .about("Release the hold of a case")
+                .arg(case)
+                .arg(holds.clone())
+                .arg(audit.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("hold-list")
+                .about("List the held cases")
+                .arg(holds.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("sweep")
+                .about("Remove expired records that are not held")
+                .arg(redis.clone())
+                .arg(namespace.clone())
+                .arg(keyring.clone())
+                .arg(holds)
+                .arg(audit)
+                .arg(
+                    Arg::with_name("retention")
+                        .long("retention")
+                        .takes_value(true)
+                        .required(true)
+                        .help("Remove records processed at least this many seconds ago"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reseal")
+                .about("Encrypt the records stored in the clear by an earlier server version")
+                .arg(redis)
+                .arg(namespace)
+                .arg(keyring),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("keygen", Some(_)) => keygen(),
+        ("keyring-generate", Some(args)) => keyring_generate(args),
+        ("keyring-rotate", Some(args)) => keyring_rotate(args),
+        ("keyring-list", Some(args)) => keyring_list(args),
+        ("snapshot-export", Some(args)) => snapshot_export(args),
+        ("snapshot-import", Some(args)) => snapshot_import(args),
+        ("new-message", Some(args)) => new_message(args),
+        ("tag", Some(args)) => tag(args),
+        ("process", Some(args)) => process(args),
+        ("verify", Some(args)) => verify(args),
+        ("trace", Some(args)) => trace(args),
+        ("hold", Some(args)) => hold(args),
+        ("hold-release", Some(args)) => hold_release(args),
+        ("hold-list", Some(args)) => hold_list(args),
+        ("sweep", Some(args)) => sweep(args),
+        ("reseal", Some(args)) => reseal(args),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
@@ -5,54 +5,430 @@ extern crate rocket;
 #[macro_use]
 extern crate rocket_contrib;
 
+use rocket::fairing::AdHoc;
+use rocket::http::ContentType;
+use rocket::response::content::Content;
 use rocket::response::status::BadRequest;
+use rocket::State;
 use rocket_contrib::databases::redis;
 use rocket_contrib::json::Json;
 use serde::{Deserialize, Serialize};
 
-use tracing::path::*;
+use tracing::analysis::{cascade_stats, CascadeStats};
+use tracing::encrypted::EncryptedStore;
+use tracing::export::{Export, Format};
+use tracing::keyring::KeyringFile;
+use tracing::pseudonym::PseudonymousStore;
+use tracing::store::{check_namespace, Namespaced};
+use tracing::{path, tree, Participant};
 
 #[database("redis")]
 struct DbConn(redis::Connection);
 
+// The records are kept under the namespace configured as `namespace`, by default the whole database.
+// The server does not start with a namespace `Namespaced` rejects.
+struct Namespace(String);
+
 #[derive(Serialize, Deserialize)]
-struct ProcessRequestData {
-    stag: SenderTraceTag,
-    sid: u32,
-    rid: u32,
+struct ProcessRequestData<T> {
+    stag: T,
+    sid: Participant,
+    rid: Participant,
 }
 
 #[derive(Serialize, Deserialize)]
-struct TraceRequestData {
-    m: String,
-    tmd: TraceMetadata,
-    uid: u32,
+struct GroupProcessRequestData<T> {
+    stags: Vec<T>,
+    sid: Participant,
+    rids: Vec<Participant>,
+}
+
+// Messages are sent as text or, when they are binary like a media commitment message, as an array
+// of bytes.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Message {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl Message {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Message::Text(m) => m.as_bytes(),
+            Message::Bytes(m) => m,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct TraceRequestData<T> {
+    m: Message,
+    tmd: T,
+    uid: Participant,
+}
+
+#[derive(Serialize)]
+struct TreeTraceResponse {
+    #[serde(flatten)]
+    outcome: tree::TraceOutcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<CascadeStats>,
+}
+
+// Without a format the full trace outcome is returned as JSON.
+fn render_trace(
+    outcome: &impl Serialize,
+    graph: &(impl Export + ?Sized),
+    format: Option<String>,
+) -> Result<Content<String>, BadRequest<String>> {
+    let format = match format {
+        Some(f) => f.parse::<Format>().map_err(|e| BadRequest(Some(e)))?,
+        None => {
+            return Ok(Content(
+                ContentType::JSON,
+                serde_json::to_string(outcome).unwrap(),
+            ))
+        }
+    };
+    let content_type = match format {
+        Format::Json => ContentType::JSON,
+        Format::Dot => ContentType::new("text", "vnd.graphviz"),
+        Format::Newick => ContentType::Plain,
+    };
+    Ok(Content(content_type, graph.export(format)))
 }
 
 #[post("/process", format = "json", data = "<data>")]
 fn process(
     conn: DbConn,
-    data: Json<ProcessRequestData>,
-) -> Result<Json<RecTraceTag>, BadRequest<String>> {
+    keyring: State<KeyringFile>,
+    namespace: State<Namespace>,
+    data: Json<ProcessRequestData<path::SenderTraceTag>>,
+) -> Result<Json<path::RecTraceTag>, BadRequest<String>> {
     let data = data.into_inner();
-    let rec_tag = svr_process(&*conn, &data.stag, data.sid, data.rid);
+    let keyring = keyring.keyring();
+    let conn = Namespaced::new(&*conn, &namespace.0).unwrap();
+    let encrypted = EncryptedStore::new(&conn, &keyring);
+    let store = PseudonymousStore::new(&encrypted, &keyring);
+    let rec_tag = path::svr_process(&store, &data.stag, &data.sid, &data.rid);
     match rec_tag {
         Some(t) => Ok(Json(t)),
         None => Err(BadRequest(None)),
     }
 }
 
+// All recipients' tags are stored or, on any conflict, none are.
+#[post("/process/group", format = "json", data = "<data>")]
+fn process_group(
+    conn: DbConn,
+    keyring: State<KeyringFile>,
+    namespace: State<Namespace>,
+    data: Json<GroupProcessRequestData<path::SenderTraceTag>>,
+) -> Result<Json<Vec<path::RecTraceTag>>, BadRequest<String>> {
+    let data = data.into_inner();
+    let keyring = keyring.keyring();
+    let conn = Namespaced::new(&*conn, &namespace.0).unwrap();
+    let encrypted = EncryptedStore::new(&conn, &keyring);
+    let store = PseudonymousStore::new(&encrypted, &keyring);
+    let rec_tags = path::svr_process_group(&store, &data.stags, &data.sid, &data.rids);
+    match rec_tags {
+        Some(t) => Ok(Json(t)),
+        None => Err(BadRequest(None)),
+    }
+}
+
+#[post("/process/batch", format = "json", data = "<data>")]
+fn process_batch(
+    conn: DbConn,
+    keyring: State<KeyringFile>,
+    namespace: State<Namespace>,
+    data: Json<Vec<ProcessRequestData<path::SenderTraceTag>>>,
+) -> Json<Vec<Option<path::RecTraceTag>>> {
+    let items: Vec<_> = data
+        .into_inner()
+        .into_iter()
+        .map(|d| (d.stag, d.sid, d.rid))
+        .collect();
+    let keyring = keyring.keyring();
+    let conn = Namespaced::new(&*conn, &namespace.0).unwrap();
+    let encrypted = EncryptedStore::new(&conn, &keyring);
+    let store = PseudonymousStore::new(&encrypted, &keyring);
+    Json(path::svr_process_batch(&store, &items))
+}
+
 // TODO: Spawn off trace in separate thread and return polling id
-#[post("/trace", format = "json", data = "<data>")]
-fn trace(conn: DbConn, data: Json<TraceRequestData>) -> Result<Json<Vec<u32>>, BadRequest<String>> {
+#[post("/trace?<format>", format = "json", data = "<data>")]
+fn trace(
+    conn: DbConn,
+    keyring: State<KeyringFile>,
+    namespace: State<Namespace>,
+    data: Json<TraceRequestData<path::TraceMetadata>>,
+    format: Option<String>,
+) -> Result<Content<String>, BadRequest<String>> {
     let data = data.into_inner();
-    let tr = svr_trace(&*conn, &data.m.as_bytes(), &data.tmd, data.uid);
-    Ok(Json(tr))
+    let keyring = keyring.keyring();
+    let conn = Namespaced::new(&*conn, &namespace.0).unwrap();
+    let encrypted = EncryptedStore::new(&conn, &keyring);
+    let store = PseudonymousStore::new(&encrypted, &keyring);
+    let tr = path::svr_trace(&store, data.m.as_bytes(), &data.tmd, &data.uid);
+    render_trace(&tr, &tr.path[..], format)
 }
 
-fn main() {
-    rocket::ignite()
+#[post("/tree/process", format = "json", data = "<data>")]
+fn tree_process(
+    conn: DbConn,
+    keyring: State<KeyringFile>,
+    namespace: State<Namespace>,
+    data: Json<ProcessRequestData<tree::SenderTraceTag>>,
+) -> Result<Json<tree::RecTraceTag>, BadRequest<String>> {
+    let data = data.into_inner();
+    let keyring = keyring.keyring();
+    let conn = Namespaced::new(&*conn, &namespace.0).unwrap();
+    let encrypted = EncryptedStore::new(&conn, &keyring);
+    let store = PseudonymousStore::new(&encrypted, &keyring);
+    let rec_tag = tree::svr_process(&store, &data.stag, &data.sid, &data.rid);
+    match rec_tag {
+        Some(t) => Ok(Json(t)),
+        None => Err(BadRequest(None)),
+    }
+}
+
+#[post("/tree/process/group", format = "json", data = "<data>")]
+fn tree_process_group(
+    conn: DbConn,
+    keyring: State<KeyringFile>,
+    namespace: State<Namespace>,
+    data: Json<GroupProcessRequestData<tree::SenderTraceTag>>,
+) -> Result<Json<Vec<tree::RecTraceTag>>, BadRequest<String>> {
+    let data = data.into_inner();
+    let keyring = keyring.keyring();
+    let conn = Namespaced::new(&*conn, &namespace.0).unwrap();
+    let encrypted = EncryptedStore::new(&conn, &keyring);
+    let store = PseudonymousStore::new(&encrypted, &keyring);
+    let rec_tags = tree::svr_process_group(&store, &data.stags, &data.sid, &data.rids);
+    match rec_tags {
+        Some(t) => Ok(Json(t)),
+        None => Err(BadRequest(None)),
+    }
+}
+
+#[post("/tree/process/batch", format = "json", data = "<data>")]
+fn tree_process_batch(
+    conn: DbConn,
+    keyring: State<KeyringFile>,
+    namespace: State<Namespace>,
+    data: Json<Vec<ProcessRequestData<tree::SenderTraceTag>>>,
+) -> Json<Vec<Option<tree::RecTraceTag>>> {
+    let items: Vec<_> = data
+        .into_inner()
+        .into_iter()
+        .map(|d| (d.stag, d.sid, d.rid))
+        .collect();
+    let keyring = keyring.keyring();
+    let conn = Namespaced::new(&*conn, &namespace.0).unwrap();
+    let encrypted = EncryptedStore::new(&conn, &keyring);
+    let store = PseudonymousStore::new(&encrypted, &keyring);
+    Json(tree::svr_process_batch(&store, &items))
+}
+
+// Cascade statistics are attached to the JSON trace outcome when `stats=true`. Exported formats
+// carry only the graph, so they cannot be combined with `stats`.
+#[post("/tree/trace?<format>&<stats>", format = "json", data = "<data>")]
+fn tree_trace(
+    conn: DbConn,
+    keyring: State<KeyringFile>,
+    namespace: State<Namespace>,
+    data: Json<TraceRequestData<tree::TraceMetadata>>,
+    format: Option<String>,
+    stats: Option<bool>,
+) -> Result<Content<String>, BadRequest<String>> {
+    if format.is_some() && stats == Some(true) {
+        return Err(BadRequest(Some(
+            "stats are only returned with the JSON trace outcome".to_string(),
+        )));
+    }
+    let data = data.into_inner();
+    let keyring = keyring.keyring();
+    let conn = Namespaced::new(&*conn, &namespace.0).unwrap();
+    let encrypted = EncryptedStore::new(&conn, &keyring);
+    let store = PseudonymousStore::new(&encrypted, &keyring);
+    let tr = tree::svr_trace(&store, data.m.as_bytes(), &data.tmd, &data.uid);
+    let tree = tr.tree.to_tree();
+    let resp = TreeTraceResponse {
+        stats: match stats {
+            Some(true) => Some(cascade_stats(&tree)),
+            _ => None,
+        },
+        outcome: tr,
+    };
+    render_trace(&resp, &tree, format)
+}
+
+// Records are stored encrypted, and their participant ids sealed again on their own, under the
+// keyring at the path configured as `keyring`. The server does not start without one, since a fresh
+// key could not read the records already stored. Keys rotated in the file are picked up by the
+// running server.
+fn load_keyring(path: &str) -> Result<KeyringFile, String> {
+    if !std::path::Path::new(path).exists() {
+        return Err(format!(
+            "Keyring {} does not exist; create it with `tracing-cli keyring-generate --keyring {}`",
+            path, path
+        ));
+    }
+    KeyringFile::open(path).map_err(|e| format!("Could not load keyring {}: {}", path, e))
+}
+
+fn build(rocket: rocket::Rocket) -> rocket::Rocket {
+    rocket
         .attach(DbConn::fairing())
-        .mount("/", routes![process, trace])
-        .launch();
+        .attach(AdHoc::on_attach("Namespace", |rocket| {
+            let namespace = rocket
+                .config()
+                .get_str("namespace")
+                .unwrap_or("")
+                .to_string();
+            match check_namespace(&namespace) {
+                Ok(()) => Ok(rocket.manage(Namespace(namespace))),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .attach(AdHoc::on_attach("Keyring", |rocket| {
+            let path = rocket.config().get_str("keyring").unwrap_or("keyring.json");
+            match load_keyring(path) {
+                Ok(keyring) => Ok(rocket.manage(keyring)),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Err(rocket)
+                }
+            }
+        }))
+        .mount(
+            "/",
+            routes![
+                process,
+                process_group,
+                process_batch,
+                trace,
+                tree_process,
+                tree_process_group,
+                tree_process_batch,
+                tree_trace
+            ],
+        )
+}
+
+fn main() {
+    build(rocket::ignite()).launch();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::config::{Config, Environment, Value};
+    use rocket::local::Client;
+    use rocket_contrib::json::JsonValue;
+    use std::collections::HashMap;
+    use tracing::keyring::Keyring;
+    use tracing::media;
+
+    fn client(keyring: &str) -> Client {
+        Keyring::generate().save(keyring).unwrap();
+        let mut redis = HashMap::new();
+        redis.insert("url", Value::from("redis://127.0.0.1:6379/"));
+        let mut databases = HashMap::new();
+        databases.insert("redis", Value::from(redis));
+        let config = Config::build(Environment::Development)
+            .extra("databases", databases)
+            .extra("keyring", keyring)
+            .finalize()
+            .unwrap();
+        Client::new(build(rocket::custom(config))).unwrap()
+    }
+
+    fn post<T: serde::de::DeserializeOwned>(client: &Client, uri: &str, body: JsonValue) -> T {
+        let mut resp = client
+            .post(uri)
+            .header(ContentType::JSON)
+            .body(body.to_string())
+            .dispatch();
+        serde_json::from_str(&resp.body_string().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn trace_media() {
+        let keyring = std::env::temp_dir().join(format!("keyring-{}.json", std::process::id()));
+        let keyring = keyring.to_str().unwrap();
+        let client = client(keyring);
+
+        let attachment: Vec<u8> = (0..media::CHUNK_SIZE * 3 / 2).map(|i| i as u8).collect();
+        let m = media::commit(&attachment).message();
+        assert!(String::from_utf8(m.clone()).is_err());
+
+        let k = [1u8; 16];
+        let md0 = path::new_message(&m);
+        let rtag: path::RecTraceTag = post(
+            &client,
+            "/process",
+            json!({
+                "stag": path::generate_tag(&k, &m, &md0),
+                "sid": Participant::from(0),
+                "rid": Participant::from(1),
+            }),
+        );
+        let md1 = path::verify_tag(&k, &m, &rtag).unwrap();
+        let tr: path::TraceOutcome = post(
+            &client,
+            "/trace",
+            json!({"m": m, "tmd": md1, "uid": Participant::from(1)}),
+        );
+        assert_eq!(vec![Participant::from(1), Participant::from(0)], tr.path);
+        assert_eq!(tracing::StopReason::ReachedOrigin, tr.stop);
+
+        let md0 = tree::new_message(&m);
+        let rtag: tree::RecTraceTag = post(
+            &client,
+            "/tree/process",
+            json!({
+                "stag": tree::generate_tag(&k, &m, &md0, 0),
+                "sid": Participant::from(0),
+                "rid": Participant::from(1),
+            }),
+        );
+        let md1 = tree::verify_tag_wellformed(&k, &m, &rtag).unwrap();
+        let tr: tree::TraceOutcome = post(
+            &client,
+            "/tree/trace",
+            json!({"m": m, "tmd": md1, "uid": Participant::from(1)}),
+        );
+        assert_eq!(Participant::from(0), tr.tree.uid);
+        assert_eq!(Participant::from(1), tr.tree.edges[0].child.uid);
+
+        // Text messages are still accepted as strings
+        let m = "Plaintext";
+        let md0 = path::new_message(m.as_bytes());
+        let rtag: path::RecTraceTag = post(
+            &client,
+            "/process",
+            json!({
+                "stag": path::generate_tag(&k, m.as_bytes(), &md0),
+                "sid": Participant::from(2),
+                "rid": Participant::from(3),
+            }),
+        );
+        let md1 = path::verify_tag(&k, m.as_bytes(), &rtag).unwrap();
+        let tr: path::TraceOutcome = post(
+            &client,
+            "/trace",
+            json!({"m": m, "tmd": md1, "uid": Participant::from(3)}),
+        );
+        assert_eq!(vec![Participant::from(3), Participant::from(2)], tr.path);
+
+        let conn = DbConn::get_one(client.rocket()).unwrap();
+        let _: () = redis::cmd("FLUSHDB").query(&*conn).unwrap();
+        std::fs::remove_file(keyring).unwrap();
+    }
 }
@@ -0,0 +1,415 @@
+use clap::{App, Arg, ArgMatches};
+use rand::distributions::{Distribution, Pareto, Poisson};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use tracing::path::forward;
+use tracing::store::{Entry, MemoryStore, Record, Store};
+use tracing::{path, tree};
+
+// Counts what the protocol writes so storage growth can be reported for any backend.
+struct CountingStore<S> {
+    inner: S,
+    records: Cell<usize>,
+    bytes: Cell<usize>,
+}
+
+impl<S: Store> Store for CountingStore<S> {
+    fn exists(&self, addr: &[u8]) -> bool {
+        self.inner.exists(addr)
+    }
+
+    fn get(&self, addr: &[u8]) -> Option<Record> {
+        self.inner.get(addr)
+    }
+
+    fn insert(&self, addr: &[u8], record: &[(&str, &[u8])]) -> bool {
+        let stored = self.inner.insert(addr, record);
+        if stored {
+            let size: usize = record.iter().map(|(f, v)| f.len() + v.len()).sum();
+            self.records.set(self.records.get() + 1);
+            self.bytes.set(self.bytes.get() + addr.len() + size);
+        }
+        stored
+    }
+
+    fn insert_all(&self, records: &[Entry]) -> bool {
+        let stored = self.inner.insert_all(records);
+        if stored {
+            for (addr, record) in records {
+                let size: usize = record.iter().map(|(f, v)| f.len() + v.len()).sum();
+                self.records.set(self.records.get() + 1);
+                self.bytes.set(self.bytes.get() + addr.len() + size);
+            }
+        }
+        stored
+    }
+}
+
+trait Scheme {
+    type Metadata: Clone;
+
+    fn new_message(m: &[u8]) -> Self::Metadata;
+
+    // Runs generate_tag, svr_process and verify_tag for one forward, returning the receiver's
+    // metadata and the time spent in svr_process.
+    fn send(
+        store: &impl Store,
+        m: &[u8],
+        md: &Self::Metadata,
+        ctr: u32,
+        sid: u32,
+        rid: u32,
+    ) -> (Self::Metadata, Duration);
+
+    fn trace(store: &impl Store, m: &[u8], md: &Self::Metadata, uid: u32);
+}
+
+struct PathScheme;
+
+impl Scheme for PathScheme {
+    type Metadata = path::TraceMetadata;
+
+    fn new_message(m: &[u8]) -> Self::Metadata {
+        path::new_message(m)
+    }
+
+    fn send(
+        store: &impl Store,
+        m: &[u8],
+        md: &Self::Metadata,
+        _ctr: u32,
+        sid: u32,
+        rid: u32,
+    ) -> (Self::Metadata, Duration) {
+        let k = rand::random::<[u8; 16]>();
+        let tts = path::generate_tag(&k, m, md);
+        let start = Instant::now();
+        let ttr = path::svr_process(store, &tts, &sid.into(), &rid.into()).unwrap();
+        let elapsed = start.elapsed();
+        (path::verify_tag(&k, m, &ttr).unwrap(), elapsed)
+    }
+
+    fn trace(store: &impl Store, m: &[u8], md: &Self::Metadata, uid: u32) {
+        path::svr_trace(store, m, md, &uid.into());
+    }
+}
+
+struct PathForwardScheme;
+
+impl Scheme for PathForwardScheme {
+    type Metadata = forward::TraceMetadata;
+
+    fn new_message(m: &[u8]) -> Self::Metadata {
+        forward::new_message(m)
+    }
+
+    fn send(
+        store: &impl Store,
+        m: &[u8],
+        md: &Self::Metadata,
+        ctr: u32,
+        sid: u32,
+        rid: u32,
+    ) -> (Self::Metadata, Duration) {
+        let k = rand::random::<[u8; 16]>();
+        let tts = forward::generate_tag(&k, m, md, ctr);
+        let start = Instant::now();
+        let ttr = forward::svr_process(store, &tts, &sid.into(), &rid.into()).unwrap();
+        let elapsed = start.elapsed();
+        (forward::verify_tag(&k, m, &ttr).unwrap(), elapsed)
+    }
+
+    fn trace(store: &impl Store, m: &[u8], md: &Self::Metadata, uid: u32) {
+        forward::svr_trace(store, m, md, &uid.into());
+    }
+}
+
+struct TreeScheme;
+
+impl Scheme for TreeScheme {
+    type Metadata = tree::TraceMetadata;
+
+    fn new_message(m: &[u8]) -> Self::Metadata {
+        tree::new_message(m)
+    }
+
+    fn send(
+        store: &impl Store,
+        m: &[u8],
+        md: &Self::Metadata,
+        ctr: u32,
+        sid: u32,
+        rid: u32,
+    ) -> (Self::Metadata, Duration) {
+        let k = rand::random::<[u8; 16]>();
+        let tts = tree::generate_tag(&k, m, md, ctr);
+        let start = Instant::now();
+        let ttr = tree::svr_process(store, &tts, &sid.into(), &rid.into()).unwrap();
+        let elapsed = start.elapsed();
+        (tree::verify_tag(&k, m, &ttr).unwrap(), elapsed)
+    }
+
+    fn trace(store: &impl Store, m: &[u8], md: &Self::Metadata, uid: u32) {
+        tree::svr_trace(store, m, md, &uid.into());
+    }
+}
+
+enum FanOut {
+    // Galton-Watson branching with Poisson offspring
+    Poisson(Poisson),
+    // Heavy-tailed fan-out: floor(X) - 1 for X ~ Pareto(1, alpha)
+    PowerLaw(Pareto),
+}
+
+impl FanOut {
+    fn sample(&self, rng: &mut StdRng, max: u64) -> u64 {
+        let n = match self {
+            FanOut::Poisson(d) => d.sample(rng),
+            FanOut::PowerLaw(d) => d.sample(rng).floor() as u64 - 1,
+        };
+        n.min(max)
+    }
+}
+
+struct Config {
+    cascades: usize,
+    max_size: usize,
+    max_depth: usize,
+    max_fanout: u64,
+    mutation_rate: f64,
+    traces: usize,
+}
+
+#[derive(Clone)]
+struct Node<M> {
+    uid: u32,
+    m: Vec<u8>,
+    md: M,
+    depth: usize,
+}
+
+#[derive(Default)]
+struct Report {
+    messages: usize,
+    mutations: usize,
+    process_time: Duration,
+    wall_time: Duration,
+    growth: Vec<(usize, usize, usize)>,
+    trace_latencies: Vec<Duration>,
+}
+
+fn simulate<P: Scheme, S: Store>(
+    store: &CountingStore<S>,
+    fanout: &FanOut,
+    config: &Config,
+    rng: &mut StdRng,
+) -> Report {
+    let mut report = Report::default();
+    // Reservoir sample of recipients to trace once all cascades are stored
+    let mut sample: Vec<Node<P::Metadata>> = Vec::with_capacity(config.traces);
+    let mut seen = 0;
+    let checkpoint = (config.cascades / 10).max(1);
+    let start = Instant::now();
+
+    for cascade in 0..config.cascades {
+        let m = format!("cascade {} {}", cascade, rng.gen::<u64>()).into_bytes();
+        let mut queue = VecDeque::new();
+        queue.push_back(Node {
+            uid: rng.gen(),
+            md: P::new_message(&m),
+            m,
+            depth: 0,
+        });
+        let mut size = 1;
+
+        while let Some(node) = queue.pop_front() {
+            if node.depth >= config.max_depth {
+                continue;
+            }
+            let mut ctr = 0;
+            for _ in 0..fanout.sample(rng, config.max_fanout) {
+                if size >= config.max_size {
+                    break;
+                }
+                let rid = rng.gen();
+                // A mutated forward starts a fresh message whose counters begin at zero
+                let (m, child_ctr) = if rng.gen_bool(config.mutation_rate) {
+                    report.mutations += 1;
+                    let mut m = node.m.clone();
+                    m.extend_from_slice(b" (edited)");
+                    (m, 0)
+                } else {
+                    ctr += 1;
+                    (node.m.clone(), ctr - 1)
+                };
+                let (md, elapsed) = P::send(store, &m, &node.md, child_ctr, node.uid, rid);
+                report.messages += 1;
+                report.process_time += elapsed;
+                size += 1;
+
+                let child = Node {
+                    uid: rid,
+                    m,
+                    md,
+                    depth: node.depth + 1,
+                };
+                seen += 1;
+                if sample.len() < config.traces {
+                    sample.push(child.clone());
+                } else if config.traces > 0 {
+                    let j = rng.gen_range(0, seen);
+                    if j < config.traces {
+                        sample[j] = child.clone();
+                    }
+                }
+                queue.push_back(child);
+            }
+        }
+
+        if (cascade + 1) % checkpoint == 0 || cascade + 1 == config.cascades {
+            report
+                .growth
+                .push((cascade + 1, store.records.get(), store.bytes.get()));
+        }
+    }
+    report.wall_time = start.elapsed();
+
+    for node in &sample {
+        let start = Instant::now();
+        P::trace(store, &node.m, &node.md, node.uid);
+        report.trace_latencies.push(start.elapsed());
+    }
+    report.trace_latencies.sort();
+    report
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let i = ((p * sorted.len() as f64).ceil() as usize).max(1) - 1;
+    sorted[i.min(sorted.len() - 1)]
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1e3 + f64::from(d.subsec_nanos()) / 1e6
+}
+
+fn print_report(report: &Report) {
+    println!(
+        "messages processed: {} ({} mutated forwards)",
+        report.messages, report.mutations
+    );
+    println!("storage growth:");
+    println!("  {:>10} {:>12} {:>14}", "cascades", "records", "bytes");
+    for (cascades, records, bytes) in &report.growth {
+        println!("  {:>10} {:>12} {:>14}", cascades, records, bytes);
+    }
+    if let Some((_, records, bytes)) = report.growth.last() {
+        if *records > 0 {
+            println!("  {:.1} bytes/record", *bytes as f64 / *records as f64);
+        }
+    }
+    let process_secs = millis(report.process_time) / 1e3;
+    println!(
+        "processing: {:.3}s in svr_process ({:.0} msg/s), {:.3}s wall clock",
+        process_secs,
+        report.messages as f64 / process_secs,
+        millis(report.wall_time) / 1e3
+    );
+    let latencies = &report.trace_latencies;
+    if !latencies.is_empty() {
+        println!(
+            "trace latency over {} traces: p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+            latencies.len(),
+            millis(percentile(latencies, 0.5)),
+            millis(percentile(latencies, 0.9)),
+            millis(percentile(latencies, 0.99)),
+            millis(*latencies.last().unwrap())
+        );
+    }
+}
+
+fn run<S: Store>(store: S, args: &ArgMatches, fanout: &FanOut, config: &Config) {
+    let store = CountingStore {
+        inner: store,
+        records: Cell::new(0),
+        bytes: Cell::new(0),
+    };
+    let seed = args.value_of("seed").unwrap().parse::<u64>().unwrap();
+    let mut rng = StdRng::seed_from_u64(seed);
+    let report = match args.value_of("scheme").unwrap() {
+        "tree" => simulate::<TreeScheme, _>(&store, fanout, config, &mut rng),
+        "path-forward" => simulate::<PathForwardScheme, _>(&store, fanout, config, &mut rng),
+        _ => simulate::<PathScheme, _>(&store, fanout, config, &mut rng),
+    };
+    print_report(&report);
+}
+
+fn main() {
+    let arg = |name: &'static str, default: &'static str, help: &'static str| {
+        Arg::with_name(name)
+            .long(name)
+            .takes_value(true)
+            .default_value(default)
+            .help(help)
+    };
+    let args = App::new("tracing-sim")
+        .about("Simulates forwarding cascades through the traceback protocol")
+        .arg(arg("scheme", "tree", "Traceback scheme").possible_values(&[
+            "path",
+            "path-forward",
+            "tree",
+        ]))
+        .arg(arg("backend", "memory", "Storage backend").possible_values(&["memory", "redis"]))
+        .arg(arg(
+            "redis-url",
+            "redis://127.0.0.1:6379/",
+            "Redis backend URL",
+        ))
+        .arg(
+            arg("model", "galton-watson", "Fan-out model")
+                .possible_values(&["galton-watson", "power-law"]),
+        )
+        .arg(arg(
+            "mean-offspring",
+            "0.9",
+            "Poisson mean for galton-watson",
+        ))
+        .arg(arg("alpha", "2.0", "Pareto shape for power-law"))
+        .arg(arg("max-fanout", "100", "Cap on forwards per user"))
+        .arg(arg("cascades", "100", "Number of cascades"))
+        .arg(arg("max-size", "10000", "Cap on messages per cascade"))
+        .arg(arg("max-depth", "50", "Cap on cascade depth"))
+        .arg(arg(
+            "mutation-rate",
+            "0.01",
+            "Probability a forward edits the message",
+        ))
+        .arg(arg("traces", "1000", "Number of recipients traced"))
+        .arg(arg("seed", "0", "Seed for the cascade structure"))
+        .get_matches();
+
+    let num = |name: &str| args.value_of(name).unwrap().parse::<f64>().unwrap();
+    let fanout = match args.value_of("model").unwrap() {
+        "power-law" => FanOut::PowerLaw(Pareto::new(1.0, num("alpha"))),
+        _ => FanOut::Poisson(Poisson::new(num("mean-offspring"))),
+    };
+    let config = Config {
+        cascades: num("cascades") as usize,
+        max_size: num("max-size") as usize,
+        max_depth: num("max-depth") as usize,
+        max_fanout: num("max-fanout") as u64,
+        mutation_rate: num("mutation-rate"),
+        traces: num("traces") as usize,
+    };
+
+    match args.value_of("backend").unwrap() {
+        "redis" => {
+            let client = redis::Client::open(args.value_of("redis-url").unwrap()).unwrap();
+            run(client.get_connection().unwrap(), &args, &fanout, &config);
+        }
+        _ => run(MemoryStore::new(), &args, &fanout, &config),
+    }
+}
@@ -0,0 +1,88 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use tracing::path::*;
+use tracing::store::{MemoryStore, Store};
+
+const MESSAGE_SIZES: [usize; 4] = [16, 256, 4096, 65536];
+const PATH_LENGTHS: [u32; 4] = [1, 10, 100, 1000];
+
+fn mock_send(
+    store: &impl Store,
+    m: &[u8],
+    tmd: &TraceMetadata,
+    sid: u32,
+    rid: u32,
+) -> TraceMetadata {
+    let k = rand::random::<[u8; 16]>();
+    let tts = generate_tag(&k, m, tmd);
+    let ttr = svr_process(store, &tts, &sid.into(), &rid.into()).unwrap();
+    verify_tag(&k, m, &ttr).unwrap()
+}
+
+fn bench_tag_gen(c: &mut Criterion) {
+    let mut group = c.benchmark_group("path/tag_gen");
+    for &size in MESSAGE_SIZES.iter() {
+        let m = vec![0u8; size];
+        let k = rand::random::<[u8; 16]>();
+        let md = new_message(&m);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &m, |b, m| {
+            b.iter(|| generate_tag(&k, m, &md))
+        });
+    }
+    group.finish();
+}
+
+fn bench_tag_receive(c: &mut Criterion) {
+    let mut group = c.benchmark_group("path/tag_receive");
+    for &size in MESSAGE_SIZES.iter() {
+        let store = MemoryStore::new();
+        let m = vec![0u8; size];
+        let k = rand::random::<[u8; 16]>();
+        let tts = generate_tag(&k, &m, &new_message(&m));
+        let ttr = svr_process(&store, &tts, &0.into(), &1.into()).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &m, |b, m| {
+            b.iter(|| verify_tag(&k, m, &ttr))
+        });
+    }
+    group.finish();
+}
+
+fn bench_tag_process(c: &mut Criterion) {
+    let store = MemoryStore::new();
+    let m = [0u8; 256];
+    let md = new_message(&m);
+    c.bench_function("path/tag_process", |b| {
+        b.iter_batched(
+            || generate_tag(&rand::random::<[u8; 16]>(), &m, &md),
+            |tts| svr_process(&store, &tts, &0.into(), &1.into()),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn bench_trace_path(c: &mut Criterion) {
+    let mut group = c.benchmark_group("path/trace");
+    for &len in PATH_LENGTHS.iter() {
+        let store = MemoryStore::new();
+        let m = [0u8; 256];
+        let mut tmd = new_message(&m);
+        for i in 0..len {
+            tmd = mock_send(&store, &m, &tmd, i, i + 1);
+        }
+        group.bench_with_input(BenchmarkId::from_parameter(len), &len, |b, &len| {
+            b.iter(|| svr_trace(&store, &m, &tmd, &len.into()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_tag_gen,
+    bench_tag_receive,
+    bench_tag_process,
+    bench_trace_path
+);
+criterion_main!(benches);
@@ -0,0 +1,98 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+
+use tracing::store::{MemoryStore, Store};
+use tracing::tree::*;
+
+const MESSAGE_SIZES: [usize; 4] = [16, 256, 4096, 65536];
+const TREE_SHAPES: [(u32, u32); 6] = [(2, 2), (2, 4), (4, 2), (4, 3), (6, 2), (3, 8)];
+
+fn mock_send(
+    store: &impl Store,
+    m: &[u8],
+    tmd: &TraceMetadata,
+    ctr: u32,
+    sid: u32,
+    rid: u32,
+) -> TraceMetadata {
+    let k = rand::random::<[u8; 16]>();
+    let tts = generate_tag(&k, m, tmd, ctr);
+    let ttr = svr_process(store, &tts, &sid.into(), &rid.into()).unwrap();
+    verify_tag(&k, m, &ttr).unwrap()
+}
+
+fn mock_tree(store: &impl Store, m: &[u8], tmd: &TraceMetadata, depth: u32, span: u32, uid: u32) {
+    if depth == 0 {
+        return;
+    }
+    for i in 0..span {
+        let rid = rand::random::<u32>();
+        let tmd_out = mock_send(store, m, tmd, i, uid, rid);
+        mock_tree(store, m, &tmd_out, depth - 1, span, rid);
+    }
+}
+
+fn bench_tag_gen(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tree/tag_gen");
+    for &size in MESSAGE_SIZES.iter() {
+        let m = vec![0u8; size];
+        let k = rand::random::<[u8; 16]>();
+        let md = new_message(&m);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &m, |b, m| {
+            b.iter(|| generate_tag(&k, m, &md, 0))
+        });
+    }
+    group.finish();
+}
+
+fn bench_tag_receive(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tree/tag_receive");
+    for &size in MESSAGE_SIZES.iter() {
+        let store = MemoryStore::new();
+        let m = vec![0u8; size];
+        let k = rand::random::<[u8; 16]>();
+        let tts = generate_tag(&k, &m, &new_message(&m), 0);
+        let ttr = svr_process(&store, &tts, &0.into(), &1.into()).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &m, |b, m| {
+            b.iter(|| verify_tag(&k, m, &ttr))
+        });
+    }
+    group.finish();
+}
+
+fn bench_tag_process(c: &mut Criterion) {
+    let store = MemoryStore::new();
+    let m = [0u8; 256];
+    let md = new_message(&m);
+    c.bench_function("tree/tag_process", |b| {
+        b.iter_batched(
+            || generate_tag(&rand::random::<[u8; 16]>(), &m, &md, 0),
+            |tts| svr_process(&store, &tts, &0.into(), &1.into()),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn bench_trace_tree(c: &mut Criterion) {
+    let mut group = c.benchmark_group("tree/trace");
+    group.sample_size(10);
+    for &(depth, span) in TREE_SHAPES.iter() {
+        let store = MemoryStore::new();
+        let m = [0u8; 256];
+        let tmd = new_message(&m);
+        mock_tree(&store, &m, &tmd, depth, span, 0);
+        let id = BenchmarkId::new(format!("depth={}", depth), format!("span={}", span));
+        group.bench_function(id, |b| b.iter(|| svr_trace(&store, &m, &tmd, &0.into())));
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_tag_gen,
+    bench_tag_receive,
+    bench_tag_process,
+    bench_trace_tree
+);
+criterion_main!(benches);
@@ -0,0 +1,31 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::str::FromStr;
+
+use tracing::export::Format;
+use tracing::{path, tree};
+
+// Anything that decodes must encode to JSON that decodes to the same value.
+fn roundtrip<T: Serialize + DeserializeOwned>(data: &[u8]) {
+    if let Ok(value) = serde_json::from_slice::<T>(data) {
+        let json = serde_json::to_vec(&value).unwrap();
+        let decoded: T = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json, serde_json::to_vec(&decoded).unwrap());
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    roundtrip::<path::TraceMetadata>(data);
+    roundtrip::<path::SenderTraceTag>(data);
+    roundtrip::<path::RecTraceTag>(data);
+    roundtrip::<path::TraceOutcome>(data);
+    roundtrip::<tree::TraceMetadata>(data);
+    roundtrip::<tree::SenderTraceTag>(data);
+    roundtrip::<tree::RecTraceTag>(data);
+    roundtrip::<tree::TraceOutcome>(data);
+    if let Ok(s) = std::str::from_utf8(data) {
+        let _ = Format::from_str(s);
+    }
+});
@@ -0,0 +1,56 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use tracing::store::MemoryStore;
+use tracing::{path, tree, Participant, StopReason};
+
+#[derive(Arbitrary, Debug)]
+enum Input {
+    Path {
+        tags: Vec<(path::SenderTraceTag, Participant, Participant)>,
+        m: Vec<u8>,
+        md: path::TraceMetadata,
+        uid: Participant,
+    },
+    Tree {
+        tags: Vec<(tree::SenderTraceTag, Participant, Participant)>,
+        m: Vec<u8>,
+        md: tree::TraceMetadata,
+        uid: Participant,
+    },
+}
+
+// A tag is stored exactly when its address was free.
+fn check_processed<T>(store: &MemoryStore, process: impl FnOnce() -> Option<T>) {
+    let before = store.len();
+    let processed = process().is_some();
+    assert_eq!(processed, store.len() == before + 1);
+}
+
+fuzz_target!(|input: Input| {
+    let store = MemoryStore::new();
+    match input {
+        Input::Path { tags, m, md, uid } => {
+            for (tts, sid, rid) in &tags {
+                check_processed(&store, || path::svr_process(&store, tts, sid, rid));
+            }
+            let trace = path::svr_trace(&store, &m, &md, &uid);
+            assert_eq!(trace.hop + 1, trace.path.len());
+            assert_eq!(
+                trace.stop == StopReason::ReachedOrigin,
+                trace.origin_confirmed
+            );
+        }
+        Input::Tree { tags, m, md, uid } => {
+            for (tts, sid, rid) in &tags {
+                check_processed(&store, || tree::svr_process(&store, tts, sid, rid));
+            }
+            let trace = tree::svr_trace(&store, &m, &md, &uid);
+            assert_eq!(
+                trace.stop == StopReason::ReachedOrigin,
+                trace.origin_confirmed
+            );
+        }
+    }
+});
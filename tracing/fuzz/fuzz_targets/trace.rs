@@ -0,0 +1,139 @@
+#![no_main]
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::cell::RefCell;
+
+use tracing::store::{Entry, MemoryStore, Record, Store};
+use tracing::{path, tree, StopReason};
+
+#[derive(Arbitrary, Debug)]
+enum Corruption {
+    Remove(u8),
+    Truncate(u8, u8),
+    Replace(u8, Vec<u8>),
+    Rename(u8, String),
+}
+
+// A cascade is built through the real protocol from `forwards` of (sender node, recipient id,
+// counter), then `corruptions` are applied to the records by insertion order as they are read.
+#[derive(Arbitrary, Debug)]
+struct Input {
+    tree: bool,
+    forwards: Vec<(u8, u32, u8)>,
+    corruptions: Vec<(u8, Corruption)>,
+}
+
+struct CorruptStore {
+    inner: MemoryStore,
+    addrs: RefCell<Vec<Vec<u8>>>,
+    corruptions: Vec<(u8, Corruption)>,
+}
+
+impl Store for CorruptStore {
+    fn exists(&self, addr: &[u8]) -> bool {
+        self.inner.exists(addr)
+    }
+
+    fn get(&self, addr: &[u8]) -> Option<Record> {
+        let mut record = self.inner.get(addr)?;
+        let i = self.addrs.borrow().iter().position(|a| a[..] == *addr);
+        for (_, corruption) in self
+            .corruptions
+            .iter()
+            .filter(|(j, _)| Some(*j as usize) == i)
+        {
+            let mut fields: Vec<String> = record.keys().cloned().collect();
+            fields.sort();
+            let field = |f: &u8| fields.get(*f as usize % fields.len().max(1)).cloned();
+            match corruption {
+                Corruption::Remove(f) => {
+                    if let Some(f) = field(f) {
+                        record.remove(&f);
+                    }
+                }
+                Corruption::Truncate(f, len) => {
+                    if let Some(value) = field(f).and_then(|f| record.get_mut(&f)) {
+                        value.truncate(*len as usize);
+                    }
+                }
+                Corruption::Replace(f, bytes) => {
+                    if let Some(f) = field(f) {
+                        record.insert(f, bytes.clone());
+                    }
+                }
+                Corruption::Rename(f, name) => {
+                    if let Some(value) = field(f).and_then(|f| record.remove(&f)) {
+                        record.insert(name.clone(), value);
+                    }
+                }
+            }
+        }
+        Some(record)
+    }
+
+    fn insert(&self, addr: &[u8], record: &[(&str, &[u8])]) -> bool {
+        let stored = self.inner.insert(addr, record);
+        if stored {
+            self.addrs.borrow_mut().push(addr.to_vec());
+        }
+        stored
+    }
+
+    fn insert_all(&self, records: &[Entry]) -> bool {
+        let stored = self.inner.insert_all(records);
+        if stored {
+            let mut addrs = self.addrs.borrow_mut();
+            addrs.extend(records.iter().map(|(addr, _)| addr.to_vec()));
+        }
+        stored
+    }
+}
+
+fn check(stop: StopReason, origin_confirmed: bool) {
+    assert_eq!(stop == StopReason::ReachedOrigin, origin_confirmed);
+}
+
+fuzz_target!(|input: Input| {
+    let store = CorruptStore {
+        inner: MemoryStore::new(),
+        addrs: RefCell::new(Vec::new()),
+        corruptions: input.corruptions,
+    };
+    let m = b"Plaintext";
+    let mut rng = StdRng::seed_from_u64(0);
+
+    if input.tree {
+        let mut nodes = vec![(0, tree::new_message_with_rng(m, &mut rng))];
+        for (i, (parent, rid, ctr)) in input.forwards.iter().enumerate() {
+            let (sid, md) = &nodes[*parent as usize % nodes.len()];
+            let k = [i as u8; 16];
+            let tts = tree::generate_tag_with_rng(&k, m, md, u32::from(*ctr), &mut rng);
+            if let Some(ttr) =
+                tree::svr_process_with_rng(&store, &tts, &(*sid).into(), &(*rid).into(), &mut rng)
+            {
+                nodes.push((*rid, tree::verify_tag(&k, m, &ttr).unwrap()));
+            }
+        }
+        for (uid, md) in &nodes {
+            let trace = tree::svr_trace(&store, m, md, &(*uid).into());
+            check(trace.stop, trace.origin_confirmed);
+        }
+    } else {
+        let mut nodes = vec![(0, path::new_message(m))];
+        for (i, (parent, rid, _)) in input.forwards.iter().enumerate() {
+            let (sid, md) = &nodes[*parent as usize % nodes.len()];
+            let k = [i as u8; 16];
+            let tts = path::generate_tag(&k, m, md);
+            if let Some(ttr) = path::svr_process(&store, &tts, &(*sid).into(), &(*rid).into()) {
+                nodes.push((*rid, path::verify_tag(&k, m, &ttr).unwrap()));
+            }
+        }
+        for (uid, md) in &nodes {
+            let trace = path::svr_trace(&store, m, md, &(*uid).into());
+            assert_eq!(trace.hop + 1, trace.path.len());
+            check(trace.stop, trace.origin_confirmed);
+        }
+    }
+});
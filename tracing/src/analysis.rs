@@ -0,0 +1,151 @@
+use crate::tree::Tree;
+use crate::UserId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+// `structural_virality` is the mean distance over all pairs of nodes (the Wiener index divided by
+// the number of pairs). `forwarders` counts distinct users that sent the message on to anyone,
+// the originator included, and `level_counts[d]` the nodes at depth `d`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct CascadeStats {
+    pub recipients: usize,
+    pub depth: usize,
+    pub max_fanout: usize,
+    pub structural_virality: f64,
+    pub forwarders: usize,
+    pub level_counts: Vec<usize>,
+}
+
+#[derive(Default)]
+struct Walk {
+    level_counts: Vec<usize>,
+    max_fanout: usize,
+    forwarders: HashSet<UserId>,
+    subtree_sizes: Vec<u64>,
+}
+
+pub fn cascade_stats(tree: &Tree) -> CascadeStats {
+    let mut walk = Walk::default();
+    let n = walk.visit(tree, 0);
+
+    // The edge above a subtree of size `s` lies on the paths between `s * (n - s)` pairs
+    let wiener: u64 = walk.subtree_sizes.iter().map(|s| s * (n - s)).sum();
+    let pairs = n * (n - 1) / 2;
+    CascadeStats {
+        recipients: n as usize - 1,
+        depth: walk.level_counts.len() - 1,
+        max_fanout: walk.max_fanout,
+        structural_virality: if pairs == 0 {
+            0.0
+        } else {
+            wiener as f64 / pairs as f64
+        },
+        forwarders: walk.forwarders.len(),
+        level_counts: walk.level_counts,
+    }
+}
+
+impl Walk {
+    fn visit(&mut self, tree: &Tree, level: usize) -> u64 {
+        if self.level_counts.len() == level {
+            self.level_counts.push(0);
+        }
+        self.level_counts[level] += 1;
+        self.max_fanout = self.max_fanout.max(tree.children.len());
+        if !tree.children.is_empty() {
+            self.forwarders.insert(tree.uid.user.clone());
+        }
+
+        let mut size = 1;
+        for child in &tree.children {
+            let child_size = self.visit(child, level + 1);
+            self.subtree_sizes.push(child_size);
+            size += child_size;
+        }
+        size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(uid: u32) -> Tree {
+        Tree {
+            uid: uid.into(),
+            children: vec![],
+        }
+    }
+
+    #[test]
+    fn single_node() {
+        let stats = cascade_stats(&leaf(0));
+        assert_eq!(0, stats.recipients);
+        assert_eq!(0, stats.depth);
+        assert_eq!(0, stats.max_fanout);
+        assert_eq!(0.0, stats.structural_virality);
+        assert_eq!(vec![1], stats.level_counts);
+    }
+
+    #[test]
+    fn chain_and_star() {
+        // 0 -> 1 -> 2 -> 3: distances 1,1,1,2,2,3 over 6 pairs
+        let chain = Tree {
+            uid: 0.into(),
+            children: vec![Tree {
+                uid: 1.into(),
+                children: vec![Tree {
+                    uid: 2.into(),
+                    children: vec![leaf(3)],
+                }],
+            }],
+        };
+        let stats = cascade_stats(&chain);
+        assert_eq!(3, stats.recipients);
+        assert_eq!(3, stats.depth);
+        assert_eq!(1, stats.max_fanout);
+        assert_eq!(3, stats.forwarders);
+        assert_eq!(10.0 / 6.0, stats.structural_virality);
+
+        // 0 -> {1, 2, 3}: distances 1,1,1,2,2,2 over 6 pairs
+        let star = Tree {
+            uid: 0.into(),
+            children: vec![leaf(1), leaf(2), leaf(3)],
+        };
+        let stats = cascade_stats(&star);
+        assert_eq!(1, stats.depth);
+        assert_eq!(3, stats.max_fanout);
+        assert_eq!(1.5, stats.structural_virality);
+        assert_eq!(vec![1, 3], stats.level_counts);
+    }
+
+    #[test]
+    fn repeated_forwarder_counted_once() {
+        let tree = Tree {
+            uid: 0.into(),
+            children: vec![
+                Tree {
+                    uid: 1.into(),
+                    children: vec![leaf(2)],
+                },
+                Tree {
+                    uid: 1.into(),
+                    children: vec![leaf(3)],
+                },
+            ],
+        };
+        assert_eq!(2, cascade_stats(&tree).forwarders);
+    }
+
+    #[test]
+    fn star_originator_forwards() {
+        let star = Tree {
+            uid: 0.into(),
+            children: (1..=5).map(leaf).collect(),
+        };
+        let stats = cascade_stats(&star);
+        assert_eq!(5, stats.recipients);
+        assert_eq!(1, stats.forwarders);
+        assert_eq!(0, cascade_stats(&leaf(0)).forwarders);
+    }
+}
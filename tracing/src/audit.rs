@@ -0,0 +1,72 @@
+use crate::retention::Swept;
+use crate::Clock;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+// An append-only record of the actions taken on stored records, one line of JSON per entry, for
+// the operators and reviewers of an investigation. Entries are never rewritten or removed.
+pub struct AuditTrail {
+    path: PathBuf,
+}
+
+// `at` is in seconds since the Unix epoch.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub at: u64,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum AuditEvent {
+    Hold {
+        case: String,
+        reason: String,
+        records: usize,
+    },
+    Release {
+        case: String,
+        records: usize,
+    },
+    Sweep(Swept),
+}
+
+impl AuditTrail {
+    pub fn new(path: impl AsRef<Path>) -> AuditTrail {
+        AuditTrail {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    // The entry is on disk when this returns.
+    pub fn append(&self, clock: &impl Clock, event: AuditEvent) -> io::Result<()> {
+        let entry = AuditEntry {
+            at: clock.now(),
+            event,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+
+    // A trail that was never written to has no entries.
+    pub fn entries(&self) -> io::Result<Vec<AuditEntry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        BufReader::new(file)
+            .lines()
+            .map(|line| Ok(serde_json::from_str(&line?)?))
+            .collect()
+    }
+}
@@ -0,0 +1,117 @@
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+// Canonicalization applied by clients to a message before tagging, and by the moderator before
+// tracing, so that benign re-encodings of the same text map to the same traced bytes. Both sides
+// must use the same settings. Messages that are not valid UTF-8 are left unchanged.
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Normalization {
+    Nfc,
+    Nfkc,
+}
+
+// `collapse_whitespace` turns every run of whitespace into a single space, and
+// `strip_variation_selectors` drops U+FE00 to U+FE0F, which clients add to or remove from emoji.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Canonicalizer {
+    pub normalization: Option<Normalization>,
+    pub collapse_whitespace: bool,
+    pub trim: bool,
+    pub strip_variation_selectors: bool,
+}
+
+impl Default for Canonicalizer {
+    fn default() -> Canonicalizer {
+        Canonicalizer {
+            normalization: Some(Normalization::Nfc),
+            collapse_whitespace: true,
+            trim: true,
+            strip_variation_selectors: true,
+        }
+    }
+}
+
+impl Canonicalizer {
+    // Leaves every message unchanged.
+    pub fn identity() -> Canonicalizer {
+        Canonicalizer {
+            normalization: None,
+            collapse_whitespace: false,
+            trim: false,
+            strip_variation_selectors: false,
+        }
+    }
+
+    pub fn canonicalize(&self, m: &[u8]) -> Vec<u8> {
+        let s = match std::str::from_utf8(m) {
+            Ok(s) => s,
+            Err(_) => return m.to_vec(),
+        };
+        let chars: Box<dyn Iterator<Item = char>> = match self.normalization {
+            Some(Normalization::Nfc) => Box::new(s.nfc()),
+            Some(Normalization::Nfkc) => Box::new(s.nfkc()),
+            None => Box::new(s.chars()),
+        };
+
+        let mut out = String::with_capacity(s.len());
+        let mut in_whitespace = false;
+        for c in chars {
+            if self.strip_variation_selectors && ('\u{fe00}'..='\u{fe0f}').contains(&c) {
+                continue;
+            }
+            if self.collapse_whitespace && c.is_whitespace() {
+                if !in_whitespace {
+                    out.push(' ');
+                }
+                in_whitespace = true;
+                continue;
+            }
+            in_whitespace = false;
+            out.push(c);
+        }
+        if self.trim {
+            out.trim().as_bytes().to_vec()
+        } else {
+            out.into_bytes()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_forms_agree() {
+        let canon = Canonicalizer::default();
+        let c = |m: &str| canon.canonicalize(m.as_bytes());
+
+        assert_eq!(c("Plain text"), c("  Plain \t\n text \r\n"));
+        assert_eq!(c("caf\u{e9}"), c("cafe\u{301}"));
+        assert_eq!(c("I \u{2764} it"), c("I \u{2764}\u{fe0f} it"));
+        assert_ne!(c("Plaintext"), c("Plain text"));
+        assert_ne!(c("\u{fb01}le"), c("file"));
+
+        let nfkc = Canonicalizer {
+            normalization: Some(Normalization::Nfkc),
+            ..Canonicalizer::default()
+        };
+        assert_eq!(
+            nfkc.canonicalize("\u{fb01}le".as_bytes()),
+            nfkc.canonicalize(b"file")
+        );
+    }
+
+    #[test]
+    fn identity_and_binary_unchanged() {
+        let m = "  cafe\u{301} \u{2764}\u{fe0f} ".as_bytes();
+        assert_eq!(m.to_vec(), Canonicalizer::identity().canonicalize(m));
+
+        let binary = [0xff, b' ', b' ', 0xfe];
+        assert_eq!(
+            binary.to_vec(),
+            Canonicalizer::default().canonicalize(&binary)
+        );
+    }
+}
@@ -0,0 +1,428 @@
+use crate::keyring::Keyring;
+use crate::prf;
+use crate::pseudonym::PseudonymousStore;
+use crate::store::{
+    decode_fields, encode_fields, Checked, Entry, MemoryStore, Record, ScanStore, Store, Tampered,
+};
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes128Gcm;
+use rand::rngs::ThreadRng;
+use rand::{CryptoRng, Rng, RngCore};
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::io;
+
+// A store that encrypts every record under the server's keyring with AES-GCM, so the stored
+// fields, including the platform's `ks_fgk`, are unreadable without the keyring. A record is
+// stored as a single `record` field holding the key version, a random nonce and the encrypted
+// fields, authenticated together with its address so it cannot be altered or moved to another
+// address unnoticed. Such a record reads as tampered through `get_checked`. Records written under
+// a key that is no longer in the keyring read as missing.
+
+const HEADER_LEN: usize = 16;
+
+pub struct EncryptedStore<'a, S, R = ThreadRng> {
+    store: &'a S,
+    keyring: &'a Keyring,
+    rng: RefCell<R>,
+}
+
+fn cipher(key: &[u8; 16]) -> Aes128Gcm {
+    Aes128Gcm::new(&prf(key, b"records").into())
+}
+
+impl<'a, S: Store> EncryptedStore<'a, S> {
+    pub fn new(store: &'a S, keyring: &'a Keyring) -> EncryptedStore<'a, S> {
+        EncryptedStore::new_with_rng(store, keyring, rand::thread_rng())
+    }
+}
+
+impl<'a, S: Store, R: RngCore + CryptoRng> EncryptedStore<'a, S, R> {
+    // The nonces of the sealed records are drawn from `rng`.
+    pub fn new_with_rng(store: &'a S, keyring: &'a Keyring, rng: R) -> EncryptedStore<'a, S, R> {
+        EncryptedStore {
+            store,
+            keyring,
+            rng: RefCell::new(rng),
+        }
+    }
+
+    fn seal(&self, addr: &[u8], record: &[(&str, &[u8])]) -> Vec<u8> {
+        let (version, key) = self.keyring.current();
+        let nonce: [u8; 12] = self.rng.borrow_mut().gen();
+        let header = [&version.to_be_bytes()[..], &nonce[..]].concat();
+        let payload = Payload {
+            msg: &encode_fields(record),
+            aad: &[addr, &header[..]].concat(),
+        };
+        let ct = cipher(key).encrypt(&nonce.into(), payload).unwrap();
+        [header, ct].concat()
+    }
+
+    fn open(&self, addr: &[u8], record: Record) -> Result<Option<Record>, Tampered> {
+        let sealed = match record.get("record") {
+            Some(sealed) if record.len() == 1 && sealed.len() >= HEADER_LEN => sealed,
+            _ => return Err(Tampered),
+        };
+        let (header, ct) = sealed.split_at(HEADER_LEN);
+        let version = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let nonce: [u8; 12] = header[4..].try_into().unwrap();
+        let key = match self.keyring.get(version) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let payload = Payload {
+            msg: ct,
+            aad: &[addr, header].concat(),
+        };
+        let fields = cipher(key)
+            .decrypt(&nonce.into(), payload)
+            .map_err(|_| Tampered)?;
+        let fields = decode_fields(&fields).ok_or(Tampered)?;
+        Ok(Some(fields.into_iter().collect()))
+    }
+}
+
+impl<'a, S: Store, R: RngCore + CryptoRng> Store for EncryptedStore<'a, S, R> {
+    fn exists(&self, addr: &[u8]) -> bool {
+        self.store.exists(addr)
+    }
+
+    fn get(&self, addr: &[u8]) -> Option<Record> {
+        self.get_checked(addr).ok().flatten()
+    }
+
+    fn get_checked(&self, addr: &[u8]) -> Result<Option<Record>, Tampered> {
+        match self.store.get_checked(addr)? {
+            Some(record) => self.open(addr, record),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&self, addr: &[u8], record: &[(&str, &[u8])]) -> bool {
+        self.store
+            .insert(addr, &[("record", &self.seal(addr, record)[..])])
+    }
+
+    fn insert_all(&self, records: &[Entry]) -> bool {
+        let sealed: Vec<_> = records
+            .iter()
+            .map(|(addr, r)| [("record", self.seal(addr, r))])
+            .collect();
+        let fields: Vec<_> = sealed.iter().map(|[(f, v)]| [(*f, &v[..])]).collect();
+        let entries: Vec<Entry> = records
+            .iter()
+            .zip(&fields)
+            .map(|((addr, _), f)| (*addr, &f[..]))
+            .collect();
+        self.store.insert_all(&entries)
+    }
+
+    fn insert_many(&self, records: &[Entry]) -> Vec<bool> {
+        let sealed: Vec<_> = records
+            .iter()
+            .map(|(addr, r)| [("record", self.seal(addr, r))])
+            .collect();
+        let fields: Vec<_> = sealed.iter().map(|[(f, v)]| [(*f, &v[..])]).collect();
+        let entries: Vec<Entry> = records
+            .iter()
+            .zip(&fields)
+            .map(|((addr, _), f)| (*addr, &f[..]))
+            .collect();
+        self.store.insert_many(&entries)
+    }
+}
+
+// Lists the decrypted records, skipping those that fail to open. `scan_checked` lists those as
+// tampered or, when their key is gone, as missing. Records under a key newer than the keyring's
+// current one were written after a rotation this keyring has not seen yet, so `scan_checked`
+// skips them rather than list them as missing.
+impl<'a, S: ScanStore, R: RngCore + CryptoRng> ScanStore for EncryptedStore<'a, S, R> {
+    fn scan_prefix(
+        &self,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], Record) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.store
+            .scan_prefix(prefix, &mut |addr, record| match self.open(addr, record) {
+                Ok(Some(record)) => f(addr, record),
+                _ => Ok(()),
+            })
+    }
+
+    fn scan_checked(&self, f: &mut dyn FnMut(&[u8], Checked) -> io::Result<()>) -> io::Result<()> {
+        let current = self.keyring.current().0;
+        self.store.scan(&mut |addr, record| {
+            if key_version(&record).map_or(false, |v| v > current) {
+                return Ok(());
+            }
+            f(addr, self.open(addr, record))
+        })
+    }
+
+    fn remove(&self, addr: &[u8]) -> io::Result<bool> {
+        self.store.remove(addr)
+    }
+
+    fn replace(&self, addr: &[u8], old: &Record, record: &[(&str, &[u8])]) -> io::Result<bool> {
+        let current = match self.store.get(addr) {
+            Some(current) => current,
+            None => return Ok(false),
+        };
+        if self.open(addr, current.clone()) != Ok(Some(old.clone())) {
+            return Ok(false);
+        }
+        let sealed = self.seal(addr, record);
+        self.store
+            .replace(addr, &current, &[("record", &sealed[..])])
+    }
+}
+
+fn key_version(record: &Record) -> Option<u32> {
+    let sealed = record.get("record").filter(|s| s.len() >= HEADER_LEN)?;
+    Some(u32::from_be_bytes(sealed[..4].try_into().unwrap()))
+}
+
+// The key versions the records in `store` are encrypted under.
+pub fn key_versions(store: &impl ScanStore) -> io::Result<BTreeSet<u32>> {
+    let mut versions = BTreeSet::new();
+    store.scan(&mut |_, record| {
+        versions.extend(key_version(&record));
+        Ok(())
+    })?;
+    Ok(versions)
+}
+
+// Rewrites the records that an earlier version stored in the clear, which have no `record` field
+// and otherwise read as tampered, sealed under the current key of `keyring` by both layers, as
+// `tracing-server` stores them. Each record is swapped for its sealed copy in one step, so a
+// failure leaves it in the clear rather than lost, and a record changed since the scan is left
+// alone. Returns how many were rewritten.
+pub fn reseal(store: &impl ScanStore, keyring: &Keyring) -> io::Result<usize> {
+    let mut legacy = Vec::new();
+    store.scan(&mut |addr, record| {
+        if !record.contains_key("record") {
+            legacy.push((addr.to_vec(), record));
+        }
+        Ok(())
+    })?;
+    let mut resealed = 0;
+    for (addr, record) in &legacy {
+        // Seal the record through both layers into a scratch store to get its stored form
+        let scratch = MemoryStore::new();
+        let encrypted = EncryptedStore::new(&scratch, keyring);
+        let fields: Vec<(&str, &[u8])> = record.iter().map(|(f, v)| (&f[..], &v[..])).collect();
+        PseudonymousStore::new(&encrypted, keyring).insert(addr, &fields);
+        let sealed = scratch.get(addr).unwrap();
+        let sealed: Vec<(&str, &[u8])> = sealed.iter().map(|(f, v)| (&f[..], &v[..])).collect();
+        if store.replace(addr, record, &sealed)? {
+            resealed += 1;
+        }
+    }
+    Ok(resealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{path, tree, Participant, StopReason};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use serde::Serialize;
+
+    fn addr(ttr: &impl Serialize) -> Vec<u8> {
+        serde_json::from_value(serde_json::to_value(ttr).unwrap()["addr"].clone()).unwrap()
+    }
+
+    fn p(uid: u32) -> Participant {
+        Participant::from(uid)
+    }
+
+    fn ids(uids: &[u32]) -> Vec<Participant> {
+        uids.iter().map(|&uid| p(uid)).collect()
+    }
+
+    // Fails every replace, as a backend that goes away partway through a reseal would.
+    struct FailingReplace(MemoryStore);
+
+    impl Store for FailingReplace {
+        fn exists(&self, addr: &[u8]) -> bool {
+            self.0.exists(addr)
+        }
+
+        fn get(&self, addr: &[u8]) -> Option<Record> {
+            self.0.get(addr)
+        }
+
+        fn insert(&self, addr: &[u8], record: &[(&str, &[u8])]) -> bool {
+            self.0.insert(addr, record)
+        }
+
+        fn insert_all(&self, records: &[Entry]) -> bool {
+            self.0.insert_all(records)
+        }
+    }
+
+    impl ScanStore for FailingReplace {
+        fn scan_prefix(
+            &self,
+            prefix: &[u8],
+            f: &mut dyn FnMut(&[u8], Record) -> io::Result<()>,
+        ) -> io::Result<()> {
+            self.0.scan_prefix(prefix, f)
+        }
+
+        fn remove(&self, addr: &[u8]) -> io::Result<bool> {
+            self.0.remove(addr)
+        }
+
+        fn replace(&self, _: &[u8], _: &Record, _: &[(&str, &[u8])]) -> io::Result<bool> {
+            Err(io::Error::new(io::ErrorKind::Other, "connection lost"))
+        }
+    }
+
+    fn overwrite(conn: &redis::Connection, addr: &[u8], record: &[u8]) {
+        let _: () = redis::cmd("HSET")
+            .arg(addr)
+            .arg("record")
+            .arg(record)
+            .query(conn)
+            .unwrap();
+    }
+
+    #[test]
+    fn path_record_tampered() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+        let keyring = Keyring::generate();
+        let store = EncryptedStore::new(&conn, &keyring);
+        let m = b"Plaintext";
+
+        let mut tmd = path::new_message(m);
+        let mut addrs = Vec::new();
+        for i in 0..3 {
+            let k = rand::random::<[u8; 16]>();
+            let tts = path::generate_tag(&k, m, &tmd);
+            let ttr = path::svr_process(&store, &tts, &p(i), &p(i + 1)).unwrap();
+            addrs.push(addr(&ttr));
+            tmd = path::verify_tag(&k, m, &ttr).unwrap();
+        }
+        let record = conn.get(&addrs[1]).unwrap();
+        assert_eq!(vec!["record"], record.keys().collect::<Vec<_>>());
+        assert_eq!(
+            ids(&[3, 2, 1, 0]),
+            path::svr_trace(&store, m, &tmd, &p(3)).path
+        );
+
+        // A record moved to another address fails to open there
+        let moved = conn.get(&addrs[2]).unwrap().remove("record").unwrap();
+        overwrite(&conn, &addrs[1], &moved);
+        let trace = path::svr_trace(&store, m, &tmd, &p(3));
+        assert_eq!(ids(&[3, 2]), trace.path);
+        assert_eq!(StopReason::TamperedRecord, trace.stop);
+
+        let mut flipped = moved;
+        flipped[HEADER_LEN] ^= 1;
+        overwrite(&conn, &addrs[2], &flipped);
+        let trace = path::svr_trace(&store, m, &tmd, &p(3));
+        assert_eq!(StopReason::TamperedRecord, trace.stop);
+        assert_eq!(0, trace.hop);
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn tree_forward_tampered() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+        let keyring = Keyring::generate();
+        let encrypted = EncryptedStore::new(&conn, &keyring);
+        let store = PseudonymousStore::new(&encrypted, &keyring);
+        let m = b"Plaintext";
+
+        let k0 = rand::random::<[u8; 16]>();
+        let tmd0 = tree::new_message(m);
+        let ttr = tree::svr_process(&store, &tree::generate_tag(&k0, m, &tmd0, 0), &p(0), &p(1));
+        let tmd1 = tree::verify_tag(&k0, m, &ttr.unwrap()).unwrap();
+        let k1 = rand::random::<[u8; 16]>();
+        let ttr = tree::svr_process(&store, &tree::generate_tag(&k1, m, &tmd1, 0), &p(1), &p(2));
+        let child = addr(&ttr.unwrap());
+
+        let trace = tree::svr_trace(&store, m, &tmd1, &p(1));
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+        assert_eq!(p(2), trace.tree.edges[0].child.edges[0].child.uid);
+        assert!(!trace.tree.edges[0].child.tampered);
+
+        overwrite(&conn, &child, b"forged");
+        let trace = tree::svr_trace(&store, m, &tmd1, &p(1));
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+        assert!(trace.tree.edges[0].child.edges.is_empty());
+        assert!(trace.tree.edges[0].child.tampered);
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn reseal_plaintext_records() {
+        let inner = MemoryStore::new();
+        let keyring = Keyring::generate();
+        let encrypted = EncryptedStore::new(&inner, &keyring);
+        let store = PseudonymousStore::new(&encrypted, &keyring);
+        let m = b"Plaintext";
+
+        let k0 = rand::random::<[u8; 16]>();
+        let tts = path::generate_tag(&k0, m, &path::new_message(m));
+        let ttr = path::svr_process(&inner, &tts, &p(0), &p(1)).unwrap();
+        let tmd1 = path::verify_tag(&k0, m, &ttr).unwrap();
+        let k1 = rand::random::<[u8; 16]>();
+        let ttr = path::svr_process(&store, &path::generate_tag(&k1, m, &tmd1), &p(1), &p(2));
+        let tmd2 = path::verify_tag(&k1, m, &ttr.unwrap()).unwrap();
+        let trace = path::svr_trace(&store, m, &tmd2, &p(2));
+        assert_eq!(StopReason::TamperedRecord, trace.stop);
+
+        assert_eq!(1, reseal(&inner, &keyring).unwrap());
+        assert_eq!(0, reseal(&inner, &keyring).unwrap());
+        let trace = path::svr_trace(&store, m, &tmd2, &p(2));
+        assert_eq!(ids(&[2, 1, 0]), trace.path);
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+    }
+
+    #[test]
+    fn failed_reseal_keeps_records() {
+        let store = FailingReplace(MemoryStore::new());
+        let keyring = Keyring::generate();
+        store.insert(b"addr", &[("ct", b"ct"), ("sid", b"0"), ("rid", b"1")]);
+        assert!(reseal(&store, &keyring).is_err());
+        assert_eq!(
+            Some(b"ct".to_vec()),
+            store.get(b"addr").unwrap().remove("ct")
+        );
+    }
+
+    #[test]
+    fn seal_with_rng() {
+        let keyring = Keyring::generate();
+        let record: &[(&str, &[u8])] = &[("ct", b"ct"), ("sid", b"alice")];
+        let (inner1, inner2) = (MemoryStore::new(), MemoryStore::new());
+        let store1 = EncryptedStore::new_with_rng(&inner1, &keyring, StdRng::seed_from_u64(0));
+        let store2 = EncryptedStore::new_with_rng(&inner2, &keyring, StdRng::seed_from_u64(0));
+        store1.insert(b"addr", record);
+        store2.insert(b"addr", record);
+        assert_eq!(inner1.get(b"addr"), inner2.get(b"addr"));
+        assert_eq!(
+            Some(b"alice".to_vec()),
+            store1.get(b"addr").unwrap().remove("sid")
+        );
+
+        let old = store1.get(b"addr").unwrap();
+        assert!(!store1
+            .replace(b"addr", &Record::new(), &[("ct", b"x")])
+            .unwrap());
+        assert!(store1.replace(b"addr", &old, &[("ct", b"new")]).unwrap());
+        assert_eq!(
+            Some(b"new".to_vec()),
+            store1.get(b"addr").unwrap().remove("ct")
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use crate::tree::Tree;
+use crate::Participant;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Dot,
+    Newick,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "dot" => Ok(Format::Dot),
+            "newick" => Ok(Format::Newick),
+            _ => Err(format!("Unknown export format: {}", s)),
+        }
+    }
+}
+
+// Nodes are numbered in preorder from the root, since a user may appear more than once.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Node {
+    pub id: usize,
+    pub uid: Participant,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Edge {
+    pub source: usize,
+    pub target: usize,
+}
+
+pub trait Export {
+    fn to_graph(&self) -> Graph;
+
+    fn export(&self, format: Format) -> String {
+        match format {
+            Format::Json => self.to_json(),
+            Format::Dot => self.to_dot(),
+            Format::Newick => self.to_newick(),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(&self.to_graph()).unwrap()
+    }
+
+    fn to_dot(&self) -> String {
+        let graph = self.to_graph();
+        let mut dot = String::from("digraph trace {\n");
+        for node in &graph.nodes {
+            let label = node
+                .uid
+                .to_string()
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"");
+            dot.push_str(&format!("    n{} [label=\"{}\"];\n", node.id, label));
+        }
+        for edge in &graph.edges {
+            dot.push_str(&format!("    n{} -> n{};\n", edge.source, edge.target));
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn to_newick(&self) -> String {
+        let graph = self.to_graph();
+        let mut children = vec![Vec::new(); graph.nodes.len()];
+        for edge in &graph.edges {
+            children[edge.source].push(edge.target);
+        }
+        let mut newick = newick_node(&graph, &children, 0);
+        newick.push(';');
+        newick
+    }
+}
+
+// Labels with Newick punctuation, or underscores that readers take for spaces, are quoted.
+fn newick_label(uid: &Participant) -> String {
+    let label = uid.to_string();
+    if label.chars().any(|c| "()[]':;,_".contains(c)) {
+        format!("'{}'", label.replace('\'', "''"))
+    } else {
+        label
+    }
+}
+
+fn newick_node(graph: &Graph, children: &[Vec<usize>], id: usize) -> String {
+    let label = newick_label(&graph.nodes[id].uid);
+    if children[id].is_empty() {
+        label
+    } else {
+        let subtrees: Vec<String> = children[id]
+            .iter()
+            .map(|&c| newick_node(graph, children, c))
+            .collect();
+        format!("({}){}", subtrees.join(","), label)
+    }
+}
+
+impl Export for Tree {
+    fn to_graph(&self) -> Graph {
+        let mut graph = Graph {
+            nodes: Vec::new(),
+            edges: Vec::new(),
+        };
+        push_subtree(&mut graph, self);
+        graph
+    }
+}
+
+fn push_subtree(graph: &mut Graph, tree: &Tree) {
+    let id = graph.nodes.len();
+    graph.nodes.push(Node {
+        id,
+        uid: tree.uid.clone(),
+    });
+    for child in &tree.children {
+        let target = graph.nodes.len();
+        graph.edges.push(Edge { source: id, target });
+        push_subtree(graph, child);
+    }
+}
+
+// A path trace lists users from the reporter back to the source; the exported graph is rooted at
+// the source.
+impl Export for [Participant] {
+    fn to_graph(&self) -> Graph {
+        let nodes: Vec<Node> = self
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(id, uid)| Node {
+                id,
+                uid: uid.clone(),
+            })
+            .collect();
+        let edges = (1..nodes.len())
+            .map(|id| Edge {
+                source: id - 1,
+                target: id,
+            })
+            .collect();
+        Graph { nodes, edges }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(uid: u32) -> Tree {
+        Tree {
+            uid: uid.into(),
+            children: vec![],
+        }
+    }
+
+    fn sample_tree() -> Tree {
+        Tree {
+            uid: 0.into(),
+            children: vec![
+                Tree {
+                    uid: 1.into(),
+                    children: vec![leaf(3), leaf(4)],
+                },
+                leaf(2),
+            ],
+        }
+    }
+
+    #[test]
+    fn tree_to_newick() {
+        assert_eq!("((3,4)1,2)0;", sample_tree().to_newick());
+        assert_eq!("7;", leaf(7).to_newick());
+    }
+
+    #[test]
+    fn tree_to_dot() {
+        let dot = "digraph trace {\n    n0 [label=\"0\"];\n    n1 [label=\"1\"];\n    \
+                   n2 [label=\"2\"];\n    n0 -> n1;\n    n0 -> n2;\n}\n";
+        let tree = Tree {
+            uid: 0.into(),
+            children: vec![leaf(1), leaf(2)],
+        };
+        assert_eq!(dot, tree.to_dot());
+    }
+
+    #[test]
+    fn labels_escaped() {
+        let tree = Tree {
+            uid: Participant::new("a\\\"b", b""),
+            children: vec![Tree {
+                uid: Participant::new("o'neil,(x)", b""),
+                children: vec![],
+            }],
+        };
+        assert!(tree.to_dot().contains("n0 [label=\"a\\\\\\\"b\"];"));
+        assert_eq!("('o''neil,(x)')a\\\"b;", tree.to_newick());
+    }
+
+    #[test]
+    fn tree_to_json() {
+        let graph: Graph = serde_json::from_str(&sample_tree().to_json()).unwrap();
+        let uids: Vec<String> = graph.nodes.iter().map(|n| n.uid.to_string()).collect();
+        assert_eq!(vec!["0", "1", "3", "4", "2"], uids);
+        assert_eq!(4, graph.edges.len());
+        assert_eq!(
+            Edge {
+                source: 1,
+                target: 2
+            },
+            graph.edges[1]
+        );
+    }
+
+    #[test]
+    fn path_exports_from_source() {
+        let path: Vec<Participant> = vec![2.into(), 1.into(), 0.into()];
+        assert_eq!("((2)1)0;", path.to_newick());
+        let graph = path.to_graph();
+        assert_eq!(Participant::from(0), graph.nodes[0].uid);
+        assert_eq!(2, graph.edges.len());
+    }
+}
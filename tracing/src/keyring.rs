@@ -0,0 +1,384 @@
+use crate::{Clock, SystemClock};
+use log::warn;
+use rand::{CryptoRng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+// Versioned server secrets. New data is protected under the current key and names its version,
+// so data written under an older key stays readable for as long as that key is kept.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Keyring {
+    current: u32,
+    keys: BTreeMap<u32, Key>,
+}
+
+// Times are in seconds since the Unix epoch. A key is retired when a rotation replaces it as the
+// current key, after which nothing new is written under it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(from = "StoredKey")]
+pub struct Key {
+    key: [u8; 16],
+    pub created_at: u64,
+    pub retired_at: Option<u64>,
+}
+
+// Keyrings saved before keys had times store each key as its bytes alone. Such keys read as
+// created at 0 and never retired.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredKey {
+    Timed {
+        key: [u8; 16],
+        created_at: u64,
+        retired_at: Option<u64>,
+    },
+    Bare([u8; 16]),
+}
+
+impl From<StoredKey> for Key {
+    fn from(key: StoredKey) -> Key {
+        match key {
+            StoredKey::Timed {
+                key,
+                created_at,
+                retired_at,
+            } => Key {
+                key,
+                created_at,
+                retired_at,
+            },
+            StoredKey::Bare(key) => Key {
+                key,
+                created_at: 0,
+                retired_at: None,
+            },
+        }
+    }
+}
+
+impl Keyring {
+    pub fn generate() -> Keyring {
+        Keyring::generate_with_rng(&SystemClock, &mut rand::thread_rng())
+    }
+
+    pub fn generate_with_rng(clock: &impl Clock, rng: &mut (impl RngCore + CryptoRng)) -> Keyring {
+        let mut keys = BTreeMap::new();
+        keys.insert(
+            1,
+            Key {
+                key: rng.gen(),
+                created_at: clock.now(),
+                retired_at: None,
+            },
+        );
+        Keyring { current: 1, keys }
+    }
+
+    pub fn current(&self) -> (u32, &[u8; 16]) {
+        (self.current, &self.keys[&self.current].key)
+    }
+
+    pub fn get(&self, version: u32) -> Option<&[u8; 16]> {
+        self.keys.get(&version).map(|k| &k.key)
+    }
+
+    pub fn versions(&self) -> impl Iterator<Item = (u32, &Key)> {
+        self.keys.iter().map(|(v, k)| (*v, k))
+    }
+
+    // Adds a key under the next version and makes it current, returning its version.
+    pub fn rotate(&mut self) -> u32 {
+        self.rotate_with_rng(&SystemClock, &mut rand::thread_rng())
+    }
+
+    pub fn rotate_with_rng(
+        &mut self,
+        clock: &impl Clock,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> u32 {
+        let now = clock.now();
+        self.keys.get_mut(&self.current).unwrap().retired_at = Some(now);
+        self.current = self.keys.keys().next_back().unwrap() + 1;
+        self.keys.insert(
+            self.current,
+            Key {
+                key: rng.gen(),
+                created_at: now,
+                retired_at: None,
+            },
+        );
+        self.current
+    }
+
+    // Drops the keys retired at least `retention` seconds ago, once every record written under
+    // them has expired, and returns their versions.
+    pub fn prune(&mut self, retention: u64) -> Vec<u32> {
+        self.prune_with_clock(retention, &SystemClock)
+    }
+
+    pub fn prune_with_clock(&mut self, retention: u64, clock: &impl Clock) -> Vec<u32> {
+        self.prune_unused_with_clock(retention, &BTreeSet::new(), clock)
+    }
+
+    // Like `prune`, but keeps the versions in `in_use`, such as those of held records that outlive
+    // the retention period.
+    pub fn prune_unused(&mut self, retention: u64, in_use: &BTreeSet<u32>) -> Vec<u32> {
+        self.prune_unused_with_clock(retention, in_use, &SystemClock)
+    }
+
+    pub fn prune_unused_with_clock(
+        &mut self,
+        retention: u64,
+        in_use: &BTreeSet<u32>,
+        clock: &impl Clock,
+    ) -> Vec<u32> {
+        let now = clock.now();
+        let expired: Vec<u32> = self
+            .versions()
+            .filter(|(v, _)| !in_use.contains(v))
+            .filter(|(_, k)| k.retired_at.map_or(false, |t| t + retention <= now))
+            .map(|(v, _)| v)
+            .collect();
+        for v in &expired {
+            self.keys.remove(v);
+        }
+        expired
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Keyring> {
+        let keyring: Keyring = serde_json::from_reader(File::open(path)?)?;
+        if !keyring.keys.contains_key(&keyring.current) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "current key is missing",
+            ));
+        }
+        Ok(keyring)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        write_file(path.as_ref(), &serde_json::to_vec_pretty(self)?)
+    }
+
+    // Loads the keyring at `path`, applies `f` and saves it if it changed, all while holding a
+    // lock file next to it, so that two edits at once, such as a rotation during a sweep's
+    // pruning, cannot lose one another's keys.
+    pub fn edit<T>(path: impl AsRef<Path>, f: impl FnOnce(&mut Keyring) -> T) -> io::Result<T> {
+        let path = path.as_ref();
+        let _lock = Lock::acquire(&path.with_extension("lock"))?;
+        let mut keyring = Keyring::load(path)?;
+        let before = keyring.clone();
+        let out = f(&mut keyring);
+        if keyring != before {
+            keyring.save(path)?;
+        }
+        Ok(out)
+    }
+}
+
+// Waits up to `LOCK_WAIT` for another edit to finish. A lock left behind by a process that died
+// mid-edit has to be removed by hand.
+const LOCK_WAIT: Duration = Duration::from_secs(5);
+
+struct Lock(PathBuf);
+
+impl Lock {
+    fn acquire(path: &Path) -> io::Result<Lock> {
+        let start = Instant::now();
+        loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+            {
+                Ok(_) => return Ok(Lock(path.to_path_buf())),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if start.elapsed() > LOCK_WAIT {
+                        return Err(io::Error::new(
+                            io::ErrorKind::WouldBlock,
+                            format!(
+                                "{} is held by another edit; remove it if none is running",
+                                path.display()
+                            ),
+                        ));
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+// Writes to a temporary file first so a reader never sees a partial file. The file is readable
+// by its owner only.
+pub(crate) fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+// A keyring file that is read again whenever it changes on disk, so a key rotated by another
+// process is picked up without a restart. A file that fails to load leaves the last good keyring
+// in use.
+pub struct KeyringFile {
+    path: PathBuf,
+    loaded: RwLock<(FileStamp, Arc<Keyring>)>,
+}
+
+type FileStamp = Option<(SystemTime, u64)>;
+
+fn stamp(path: &Path) -> FileStamp {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+impl KeyringFile {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<KeyringFile> {
+        let path = path.as_ref().to_path_buf();
+        let stamp = stamp(&path);
+        let keyring = Keyring::load(&path)?;
+        Ok(KeyringFile {
+            path,
+            loaded: RwLock::new((stamp, Arc::new(keyring))),
+        })
+    }
+
+    pub fn keyring(&self) -> Arc<Keyring> {
+        let stamp = stamp(&self.path);
+        {
+            let loaded = self.loaded.read().unwrap();
+            if loaded.0 == stamp {
+                return loaded.1.clone();
+            }
+        }
+        let mut loaded = self.loaded.write().unwrap();
+        if loaded.0 != stamp {
+            match Keyring::load(&self.path) {
+                Ok(keyring) => *loaded = (stamp, Arc::new(keyring)),
+                Err(e) => warn!("Keeping keyring after failed reload: {}", e),
+            }
+        }
+        loaded.1.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FixedClock(u64);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> u64 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn rotate_keeps_old_keys() {
+        let mut rng = rand::thread_rng();
+        let mut keyring = Keyring::generate_with_rng(&FixedClock(100), &mut rng);
+        let (v1, k1) = keyring.current();
+        let k1 = *k1;
+        assert_eq!(2, keyring.rotate_with_rng(&FixedClock(200), &mut rng));
+        assert_eq!(3, keyring.rotate_with_rng(&FixedClock(300), &mut rng));
+        assert_eq!(3, keyring.current().0);
+        assert_eq!(Some(&k1), keyring.get(v1));
+        assert_ne!(&k1, keyring.current().1);
+
+        assert!(keyring.prune_with_clock(150, &FixedClock(340)).is_empty());
+        assert_eq!(vec![1], keyring.prune_with_clock(150, &FixedClock(350)));
+        assert_eq!(None, keyring.get(v1));
+        let in_use = [2].iter().cloned().collect();
+        assert!(keyring
+            .prune_unused_with_clock(0, &in_use, &FixedClock(350))
+            .is_empty());
+        assert_eq!(vec![2], keyring.prune_with_clock(0, &FixedClock(350)));
+        assert_eq!(
+            vec![3],
+            keyring.versions().map(|(v, _)| v).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn reload_on_change() {
+        let path = std::env::temp_dir().join(format!("keyring-{}.json", rand::random::<u64>()));
+        let mut keyring = Keyring::generate();
+        keyring.save(&path).unwrap();
+        assert_eq!(keyring, Keyring::load(&path).unwrap());
+
+        let file = KeyringFile::open(&path).unwrap();
+        assert_eq!(1, file.keyring().current().0);
+        keyring.rotate();
+        keyring.save(&path).unwrap();
+        assert_eq!(2, file.keyring().current().0);
+
+        fs::write(&path, b"{").unwrap();
+        assert_eq!(2, file.keyring().current().0);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn concurrent_edits_kept() {
+        let path = std::env::temp_dir().join(format!("keyring-{}.json", rand::random::<u64>()));
+        Keyring::generate().save(&path).unwrap();
+        let edits: Vec<_> = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                thread::spawn(move || Keyring::edit(&path, |k| k.rotate()).unwrap())
+            })
+            .collect();
+        let mut versions: Vec<u32> = edits.into_iter().map(|t| t.join().unwrap()).collect();
+        versions.sort();
+        assert_eq!((2..10).collect::<Vec<_>>(), versions);
+        assert_eq!(9, Keyring::load(&path).unwrap().versions().count());
+        assert!(!path.with_extension("lock").exists());
+
+        // An edit that changes nothing leaves the file alone
+        let before = stamp(&path);
+        assert!(Keyring::edit(&path, |k| k.prune(3600)).unwrap().is_empty());
+        assert_eq!(before, stamp(&path));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_untimed_keys() {
+        let path = std::env::temp_dir().join(format!("keyring-{}.json", rand::random::<u64>()));
+        let k1: Vec<String> = (0..16).map(|b| b.to_string()).collect();
+        let json = format!(
+            "{{\"current\":2,\"keys\":{{\"1\":[{}],\"2\":[{}]}}}}",
+            k1.join(","),
+            vec!["7"; 16].join(",")
+        );
+        fs::write(&path, json).unwrap();
+
+        let keyring = Keyring::load(&path).unwrap();
+        assert_eq!((2, &[7; 16]), keyring.current());
+        let (_, key) = keyring.versions().next().unwrap();
+        assert_eq!((0, None), (key.created_at, key.retired_at));
+
+        // The keys are saved with their times from then on
+        keyring.save(&path).unwrap();
+        assert_eq!(keyring, Keyring::load(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,14 +1,161 @@
-#![feature(test)]
-
 use aes_soft::block_cipher_trait::generic_array::GenericArray;
 use aes_soft::block_cipher_trait::BlockCipher;
 use aes_soft::Aes128;
 use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
 use sha3::{Digest, Sha3_256};
+use std::fmt;
+use std::io::{self, Read};
+use std::time::{SystemTime, UNIX_EPOCH};
 
+pub mod analysis;
+pub mod audit;
+pub mod canon;
+pub mod encrypted;
+pub mod export;
+pub mod keyring;
+pub mod media;
 pub mod path;
+pub mod pseudonym;
+pub mod retention;
+pub mod snapshot;
+pub mod store;
 pub mod tree;
 
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum StopReason {
+    ReachedOrigin,
+    MissingRecord,
+    IdentityMismatch,
+    MalformedForwardGeneratorKey,
+    MalformedGeneratorKeyUsage,
+    // A stored record is missing fields or holds values of the wrong length
+    MalformedRecord,
+    // A stored record failed its integrity check
+    TamperedRecord,
+}
+
+// An opaque user id, such as an account id or a pseudonymous handle. Ids converted from integers
+// are their decimal digits.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct UserId(pub Vec<u8>);
+
+impl UserId {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&[u8]> for UserId {
+    fn from(id: &[u8]) -> UserId {
+        UserId(id.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for UserId {
+    fn from(id: Vec<u8>) -> UserId {
+        UserId(id)
+    }
+}
+
+impl From<&str> for UserId {
+    fn from(id: &str) -> UserId {
+        UserId(id.as_bytes().to_vec())
+    }
+}
+
+impl From<u32> for UserId {
+    fn from(id: u32) -> UserId {
+        UserId(id.to_string().into_bytes())
+    }
+}
+
+// Printable ASCII ids are shown as text and any other id in hex after `0x`. Text ids starting with
+// `0x` are shown in hex too, so the two forms never collide.
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = self.0.iter().all(|b| b.is_ascii_graphic() && *b != b'/');
+        if text && !self.0.starts_with(b"0x") {
+            write!(f, "{}", String::from_utf8_lossy(&self.0))
+        } else {
+            write!(f, "0x")?;
+            write_hex(f, &self.0)
+        }
+    }
+}
+
+// A device of a user, identified by opaque bytes that are empty for users with a single device.
+// Traces match senders to recipients by user, so a message received on one of a user's devices
+// and forwarded from another continues the trace.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct Participant {
+    pub user: UserId,
+    #[serde(default)]
+    pub device: Vec<u8>,
+}
+
+impl Participant {
+    pub fn new(user: impl Into<UserId>, device: &[u8]) -> Participant {
+        Participant {
+            user: user.into(),
+            device: device.to_vec(),
+        }
+    }
+
+    pub fn same_user(&self, other: &Participant) -> bool {
+        self.user == other.user
+    }
+}
+
+impl From<UserId> for Participant {
+    fn from(user: UserId) -> Participant {
+        Participant::new(user, &[])
+    }
+}
+
+impl From<u32> for Participant {
+    fn from(user: u32) -> Participant {
+        Participant::new(user, &[])
+    }
+}
+
+// The user id, followed by the device in hex when there is one.
+impl fmt::Display for Participant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.user)?;
+        if !self.device.is_empty() {
+            write!(f, "/")?;
+            write_hex(f, &self.device)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_hex(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    for b in bytes {
+        write!(f, "{:02x}", b)?;
+    }
+    Ok(())
+}
+
+// Source of the processing timestamps stored with each record, in seconds since the Unix epoch.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+}
+
 fn hash(x: &[u8]) -> [u8; 16] {
     let mut y: [u8; 16] = Default::default();
     y.copy_from_slice(&Sha3_256::digest(x).as_slice()[0..16]);
@@ -22,13 +169,49 @@ fn prf(k: &[u8; 16], x: &[u8]) -> [u8; 16] {
 }
 
 fn crprf(k: &[u8; 16], x: &[u8]) -> [u8; 32] {
-    let mut y: [u8; 32] = Default::default();
-    let mut mac = Hmac::<Sha3_256>::new_varkey(k).unwrap();
+    let mut mac = crprf_mac(k);
     mac.input(x);
+    crprf_finish(mac)
+}
+
+fn crprf_mac(k: &[u8; 16]) -> Hmac<Sha3_256> {
+    Hmac::<Sha3_256>::new_varkey(k).unwrap()
+}
+
+fn crprf_finish(mac: Hmac<Sha3_256>) -> [u8; 32] {
+    let mut y: [u8; 32] = Default::default();
     y.copy_from_slice(&mac.result().code().as_slice());
     y
 }
 
+// Streaming counterparts of `prf` and `crprf`, equal to them on the bytes read to the end of `r`.
+fn prf_reader(k: &[u8; 16], r: &mut impl Read) -> io::Result<[u8; 16]> {
+    let mut h = Sha3_256::new();
+    h.input(k);
+    read_chunks(r, |x| h.input(x))?;
+    let mut y: [u8; 16] = Default::default();
+    y.copy_from_slice(&h.result().as_slice()[0..16]);
+    Ok(y)
+}
+
+fn crprf_reader(k: &[u8; 16], r: &mut impl Read) -> io::Result<[u8; 32]> {
+    let mut mac = crprf_mac(k);
+    read_chunks(r, |x| mac.input(x))?;
+    Ok(crprf_finish(mac))
+}
+
+fn read_chunks(r: &mut impl Read, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buf = [0; 8192];
+    loop {
+        match r.read(&mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(n) => f(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 fn encipher(k: &[u8; 16], x: &[u8; 16]) -> [u8; 16] {
     let mut y: [u8; 16] = Default::default();
     let cipher = Aes128::new(GenericArray::from_slice(k));
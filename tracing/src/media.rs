@@ -0,0 +1,269 @@
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+// Media mode traces a commitment to an attachment in place of its bytes: the root of a Merkle tree
+// over fixed-size chunks, shaped as in RFC 6962, together with the attachment length. Clients
+// hash an attachment once as it streams in, and a reported attachment, or a single chunk of it
+// with its inclusion proof, is checked against the commitment without rehashing anything else.
+
+pub const CHUNK_SIZE: usize = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Commitment {
+    pub root: [u8; 32],
+    pub len: u64,
+}
+
+// `path` lists sibling hashes from the chunk's leaf up to the root.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChunkProof {
+    pub index: u64,
+    pub path: Vec<[u8; 32]>,
+}
+
+#[derive(Default)]
+pub struct MediaHasher {
+    buf: Vec<u8>,
+    leaves: Vec<[u8; 32]>,
+    len: u64,
+}
+
+pub struct MediaTree {
+    leaves: Vec<[u8; 32]>,
+    len: u64,
+}
+
+impl Commitment {
+    // The value passed as `m` to tag generation, verification and tracing in either scheme.
+    pub fn message(&self) -> Vec<u8> {
+        [&b"media"[..], &self.root[..], &self.len.to_be_bytes()[..]].concat()
+    }
+
+    pub fn chunks(&self) -> u64 {
+        self.len.div_ceil(CHUNK_SIZE as u64)
+    }
+}
+
+impl MediaHasher {
+    pub fn new() -> MediaHasher {
+        MediaHasher::default()
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let n = (CHUNK_SIZE - self.buf.len()).min(data.len());
+            self.buf.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.buf.len() == CHUNK_SIZE {
+                self.leaves.push(leaf_hash(&self.buf));
+                self.buf.clear();
+            }
+        }
+    }
+
+    pub fn finalize(mut self) -> MediaTree {
+        if !self.buf.is_empty() {
+            self.leaves.push(leaf_hash(&self.buf));
+        }
+        MediaTree {
+            leaves: self.leaves,
+            len: self.len,
+        }
+    }
+}
+
+impl MediaTree {
+    pub fn commitment(&self) -> Commitment {
+        Commitment {
+            root: root(&self.leaves),
+            len: self.len,
+        }
+    }
+
+    pub fn prove_chunk(&self, index: u64) -> Option<ChunkProof> {
+        if index >= self.leaves.len() as u64 {
+            return None;
+        }
+        let mut path = Vec::new();
+        inclusion_path(&self.leaves, index as usize, &mut path);
+        Some(ChunkProof { index, path })
+    }
+}
+
+pub fn commit(data: &[u8]) -> Commitment {
+    let mut hasher = MediaHasher::new();
+    hasher.update(data);
+    hasher.finalize().commitment()
+}
+
+pub fn verify_attachment(data: &[u8], commitment: &Commitment) -> bool {
+    commit(data) == *commitment
+}
+
+// Every chunk but the last is `CHUNK_SIZE` bytes long; the last holds the rest of the attachment.
+pub fn verify_chunk(commitment: &Commitment, chunk: &[u8], proof: &ChunkProof) -> bool {
+    let n = commitment.chunks();
+    if proof.index >= n {
+        return false;
+    }
+    let expected_len = if proof.index == n - 1 {
+        commitment.len - proof.index * CHUNK_SIZE as u64
+    } else {
+        CHUNK_SIZE as u64
+    };
+    if chunk.len() as u64 != expected_len {
+        return false;
+    }
+
+    // RFC 9162, section 2.1.3.2
+    let mut fn_ = proof.index;
+    let mut sn = n - 1;
+    let mut r = leaf_hash(chunk);
+    for p in &proof.path {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            r = node_hash(p, &r);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    sn == 0 && r == commitment.root
+}
+
+fn leaf_hash(chunk: &[u8]) -> [u8; 32] {
+    digest(&[&[0], chunk])
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    digest(&[&[1], left, right])
+}
+
+fn digest(parts: &[&[u8]]) -> [u8; 32] {
+    let mut h = Sha3_256::new();
+    for part in parts {
+        h.input(part);
+    }
+    let mut y: [u8; 32] = Default::default();
+    y.copy_from_slice(h.result().as_slice());
+    y
+}
+
+// The left subtree holds the largest power of two of leaves smaller than their number.
+fn split(n: usize) -> usize {
+    let mut k = 1;
+    while k * 2 < n {
+        k *= 2;
+    }
+    k
+}
+
+fn root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    match leaves.len() {
+        0 => digest(&[]),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node_hash(&root(&leaves[..k]), &root(&leaves[k..]))
+        }
+    }
+}
+
+fn inclusion_path(leaves: &[[u8; 32]], index: usize, path: &mut Vec<[u8; 32]>) {
+    let n = leaves.len();
+    if n <= 1 {
+        return;
+    }
+    let k = split(n);
+    if index < k {
+        inclusion_path(&leaves[..k], index, path);
+        path.push(root(&leaves[k..]));
+    } else {
+        inclusion_path(&leaves[k..], index - k, path);
+        path.push(root(&leaves[..k]));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path;
+    use crate::store::MemoryStore;
+    use crate::Participant;
+
+    fn p(uid: u32) -> Participant {
+        Participant::from(uid)
+    }
+
+    fn attachment(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn incremental_hash_matches() {
+        let data = attachment(3 * CHUNK_SIZE + 100);
+        let mut hasher = MediaHasher::new();
+        for piece in data.chunks(1000) {
+            hasher.update(piece);
+        }
+        let c = hasher.finalize().commitment();
+        assert_eq!(commit(&data), c);
+        assert_eq!(4, c.chunks());
+        assert!(verify_attachment(&data, &c));
+
+        let mut edited = data.clone();
+        edited[CHUNK_SIZE + 7] ^= 1;
+        assert!(!verify_attachment(&edited, &c));
+        assert!(!verify_attachment(&data[..data.len() - 1], &c));
+        assert_ne!(commit(&[]), commit(&[0]));
+    }
+
+    #[test]
+    fn chunk_proofs_verify() {
+        for n in 1..=9 {
+            let data = attachment(n * CHUNK_SIZE - 5);
+            let mut hasher = MediaHasher::new();
+            hasher.update(&data);
+            let tree = hasher.finalize();
+            let c = tree.commitment();
+            for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+                let proof = tree.prove_chunk(i as u64).unwrap();
+                assert!(verify_chunk(&c, chunk, &proof));
+
+                let mut wrong = proof.clone();
+                wrong.index = (wrong.index + 1) % n as u64;
+                assert!(n == 1 || !verify_chunk(&c, chunk, &wrong));
+                let mut edited = chunk.to_vec();
+                edited[0] ^= 1;
+                assert!(!verify_chunk(&c, &edited, &proof));
+            }
+            assert!(tree.prove_chunk(n as u64).is_none());
+        }
+    }
+
+    #[test]
+    fn trace_commitment() {
+        let store = MemoryStore::new();
+        let data = attachment(2 * CHUNK_SIZE + 1);
+        let m = commit(&data).message();
+
+        let k = rand::random::<[u8; 16]>();
+        let tmd0 = path::new_message(&m);
+        let tts = path::generate_tag(&k, &m, &tmd0);
+        let ttr = path::svr_process(&store, &tts, &p(0), &p(1)).unwrap();
+        let tmd1 = path::verify_tag(&k, &m, &ttr).unwrap();
+
+        let reported = commit(&data);
+        assert!(verify_attachment(&data, &reported));
+        let trace = path::svr_trace(&store, &reported.message(), &tmd1, &p(1));
+        assert_eq!(vec![p(1), p(0)], trace.path);
+    }
+}
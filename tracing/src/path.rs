@@ -1,13 +1,19 @@
 use super::*;
-use redis::Commands;
+use crate::store::{read_array, read_int, read_participant, Entry, Record, Store, Tampered};
 use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
 
-#[derive(Serialize, Deserialize)]
+pub mod edit;
+pub mod forward;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary, Debug))]
 pub struct TraceMetadata {
     ptr: [u8; 16],
 }
 
 #[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary, Debug))]
 pub struct SenderTraceTag {
     addr: [u8; 32],
     ct: [u8; 16],
@@ -18,6 +24,19 @@ pub struct RecTraceTag {
     addr: [u8; 32],
 }
 
+// `hop` is the number of forwards walked back from the reporter before the trace stopped.
+// `processed_at[i]` is when the forward from `path[i + 1]` to `path[i]` was processed, and
+// `addrs[i]` is the address of its record.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceOutcome {
+    pub path: Vec<Participant>,
+    pub processed_at: Vec<Option<u64>>,
+    pub addrs: Vec<[u8; 32]>,
+    pub stop: StopReason,
+    pub hop: usize,
+    pub origin_confirmed: bool,
+}
+
 pub fn new_message(_m: &[u8]) -> TraceMetadata {
     TraceMetadata { ptr: [0; 16] }
 }
@@ -28,6 +47,18 @@ pub fn generate_tag(k: &[u8; 16], m: &[u8], md: &TraceMetadata) -> SenderTraceTa
     SenderTraceTag { addr: addr, ct: ct }
 }
 
+// As `generate_tag`, reading the message from `r` to its end instead of from memory.
+pub fn generate_tag_from_reader(
+    k: &[u8; 16],
+    r: &mut impl Read,
+    md: &TraceMetadata,
+) -> io::Result<SenderTraceTag> {
+    Ok(SenderTraceTag {
+        addr: crprf_reader(k, r)?,
+        ct: encipher(k, &md.ptr),
+    })
+}
+
 pub fn verify_tag(k: &[u8; 16], m: &[u8], ttr: &RecTraceTag) -> Option<TraceMetadata> {
     let addr = crprf(k, m);
     if addr != ttr.addr {
@@ -38,56 +69,236 @@ pub fn verify_tag(k: &[u8; 16], m: &[u8], ttr: &RecTraceTag) -> Option<TraceMeta
 }
 
 pub fn svr_process(
-    conn: &redis::Connection,
+    conn: &impl Store,
     tts: &SenderTraceTag,
-    sid: u32,
-    rid: u32,
+    sid: &Participant,
+    rid: &Participant,
 ) -> Option<RecTraceTag> {
-    let addr_filled: bool = conn.exists(&tts.addr).unwrap();
+    svr_process_with_clock(conn, tts, sid, rid, &SystemClock)
+}
 
-    if addr_filled {
-        None
-    } else {
-        let _: () = conn.hset(&tts.addr, "ct", &tts.ct).unwrap();
-        let _: () = conn
-            .hset_multiple(&tts.addr, &[("sid", sid), ("rid", rid)])
-            .unwrap();
-        Some(RecTraceTag {
-            addr: tts.addr.clone(),
+pub fn svr_process_with_clock(
+    conn: &impl Store,
+    tts: &SenderTraceTag,
+    sid: &Participant,
+    rid: &Participant,
+    clock: &impl Clock,
+) -> Option<RecTraceTag> {
+    svr_store_records(&[(tts, sid, rid)], clock, |entries| {
+        vec![conn.insert(entries[0].0, entries[0].1)]
+    })
+    .pop()
+    .unwrap()
+}
+
+pub fn generate_group_tags(ks: &[[u8; 16]], m: &[u8], md: &TraceMetadata) -> Vec<SenderTraceTag> {
+    ks.iter().map(|k| generate_tag(k, m, md)).collect()
+}
+
+// Processes a send to a group, one tag per recipient in `rids`. Either every recipient's record is
+// stored or, if any address is already filled, none is. A group needs at least one recipient.
+pub fn svr_process_group(
+    conn: &impl Store,
+    tts: &[SenderTraceTag],
+    sid: &Participant,
+    rids: &[Participant],
+) -> Option<Vec<RecTraceTag>> {
+    svr_process_group_with_clock(conn, tts, sid, rids, &SystemClock)
+}
+
+pub fn svr_process_group_with_clock(
+    conn: &impl Store,
+    tts: &[SenderTraceTag],
+    sid: &Participant,
+    rids: &[Participant],
+    clock: &impl Clock,
+) -> Option<Vec<RecTraceTag>> {
+    if tts.len() != rids.len() || tts.is_empty() {
+        return None;
+    }
+    let items: Vec<_> = tts
+        .iter()
+        .zip(rids)
+        .map(|(tts, rid)| (tts, sid, rid))
+        .collect();
+    svr_store_records(&items, clock, |entries| {
+        vec![conn.insert_all(entries); entries.len()]
+    })
+    .into_iter()
+    .collect()
+}
+
+// Processes independent (tag, sid, rid) items in one round trip to the store, with a result per
+// item as from `svr_process`.
+pub fn svr_process_batch(
+    conn: &impl Store,
+    items: &[(SenderTraceTag, Participant, Participant)],
+) -> Vec<Option<RecTraceTag>> {
+    svr_process_batch_with_clock(conn, items, &SystemClock)
+}
+
+pub fn svr_process_batch_with_clock(
+    conn: &impl Store,
+    items: &[(SenderTraceTag, Participant, Participant)],
+    clock: &impl Clock,
+) -> Vec<Option<RecTraceTag>> {
+    let items: Vec<_> = items
+        .iter()
+        .map(|(tts, sid, rid)| (tts, sid, rid))
+        .collect();
+    svr_store_records(&items, clock, |entries| conn.insert_many(entries))
+}
+
+// Builds the record for each item and hands them to `store`, which reports which were stored.
+fn svr_store_records(
+    items: &[(&SenderTraceTag, &Participant, &Participant)],
+    clock: &impl Clock,
+    store: impl FnOnce(&[Entry]) -> Vec<bool>,
+) -> Vec<Option<RecTraceTag>> {
+    let ts = clock.now().to_string();
+    let fields: Vec<[(&str, &[u8]); 6]> = items
+        .iter()
+        .map(|(tts, sid, rid)| {
+            [
+                ("ct", &tts.ct[..]),
+                ("sid", sid.user.as_bytes()),
+                ("sid_device", &sid.device[..]),
+                ("rid", rid.user.as_bytes()),
+                ("rid_device", &rid.device[..]),
+                ("ts", ts.as_bytes()),
+            ]
         })
+        .collect();
+    let entries: Vec<Entry> = items
+        .iter()
+        .zip(&fields)
+        .map(|((tts, _, _), fields)| (&tts.addr[..], &fields[..]))
+        .collect();
+
+    store(&entries)
+        .into_iter()
+        .zip(items)
+        .map(|(stored, (tts, _, _))| {
+            if stored {
+                Some(RecTraceTag { addr: tts.addr })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn svr_trace(
+    conn: &impl Store,
+    m: &[u8],
+    md: &TraceMetadata,
+    uid: &Participant,
+) -> TraceOutcome {
+    let mut processed_at = Vec::new();
+    let mut addrs = Vec::new();
+    let walk = walk_back(conn, m, &md.ptr, uid, usize::MAX, |addr, _, record| {
+        processed_at.push(read_int(record, "ts"));
+        addrs.push(*addr);
+        Ok(None)
+    });
+    let stop = walk.stop.unwrap();
+    TraceOutcome {
+        hop: walk.path.len() - 1,
+        path: walk.path,
+        processed_at,
+        addrs,
+        stop,
+        origin_confirmed: stop == StopReason::ReachedOrigin,
     }
 }
 
-pub fn svr_trace(conn: &redis::Connection, m: &[u8], md: &TraceMetadata, uid: u32) -> Vec<u32> {
-    let mut path = vec![uid];
-    let mut ptr = md.ptr.clone();
-    let mut addr = crprf(&md.ptr, m);
-    let mut ct: [u8; 16] = Default::default();
-
-    while conn.exists(&addr).unwrap() {
-        let ctvec: Vec<u8> = conn.hget(&addr, "ct").unwrap();
-        ct.copy_from_slice(&ctvec);
-        let rid: u32 = conn.hget(&addr, "rid").unwrap();
-        if *path.last().unwrap() != rid {
-            break;
+// `path` holds the reporter and each sender walked back to, and `ptr` is the pointer under which
+// the last of them received the message. `stop` is absent when the walk ended at its limit.
+pub(crate) struct Walk {
+    pub path: Vec<Participant>,
+    pub ptr: [u8; 16],
+    pub stop: Option<StopReason>,
+}
+
+// Walks back at most `limit` forwards from the reporter `uid`, calling `visit` with the address,
+// pointer and record of each forward walked. `visit` returns the message the sender received when
+// it differs from the one forwarded, or a reason to stop the walk before the sender.
+pub(crate) fn walk_back(
+    conn: &impl Store,
+    m: &[u8],
+    ptr: &[u8; 16],
+    uid: &Participant,
+    limit: usize,
+    mut visit: impl FnMut(&[u8; 32], &[u8; 16], &Record) -> Result<Option<Vec<u8>>, StopReason>,
+) -> Walk {
+    let mut path = vec![uid.clone()];
+    let mut ptr = *ptr;
+    let mut m = m.to_vec();
+
+    let stop = loop {
+        if path.len() > limit {
+            break None;
         }
-        path.push(conn.hget(&addr, "sid").unwrap());
+        if ptr == [0; 16] {
+            break Some(StopReason::ReachedOrigin);
+        }
+        let addr = crprf(&ptr, &m);
+        let record = match conn.get_checked(&addr) {
+            Ok(Some(record)) => record,
+            Ok(None) => break Some(StopReason::MissingRecord),
+            Err(Tampered) => break Some(StopReason::TamperedRecord),
+        };
+
+        let (ct, sid, rid) = match (
+            read_array(&record, "ct"),
+            read_participant(&record, "sid"),
+            read_participant(&record, "rid"),
+        ) {
+            (Some(ct), Some(sid), Some(rid)) => (ct, sid, rid),
+            _ => break Some(StopReason::MalformedRecord),
+        };
+        if !path.last().unwrap().same_user(&rid) {
+            break Some(StopReason::IdentityMismatch);
+        }
+        match visit(&addr, &ptr, &record) {
+            Ok(Some(parent)) => m = parent,
+            Ok(None) => (),
+            Err(stop) => break Some(stop),
+        }
+        path.push(sid);
 
         ptr = decipher(&ptr, &ct);
-        addr = crprf(&ptr, m);
-    }
-    path
+    };
+    Walk { path, ptr, stop }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::store::MemoryStore;
+    use crate::UserId;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use redis::Commands;
 
-    extern crate test;
-    use test::Bencher;
+    struct FixedClock(u64);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> u64 {
+            self.0
+        }
+    }
+
+    fn p(uid: u32) -> Participant {
+        Participant::from(uid)
+    }
+
+    fn ids(uids: &[u32]) -> Vec<Participant> {
+        uids.iter().map(|&uid| Participant::from(uid)).collect()
+    }
 
     fn mock_send(
-        conn: &redis::Connection,
+        conn: &impl Store,
         m: &[u8],
         tmd: &TraceMetadata,
         sid: u32,
@@ -95,22 +306,10 @@ mod tests {
     ) -> TraceMetadata {
         let k = rand::random::<[u8; 16]>();
         let tts = generate_tag(&k, m, &tmd);
-        let ttr = svr_process(conn, &tts, sid, rid).unwrap();
+        let ttr = svr_process(conn, &tts, &p(sid), &p(rid)).unwrap();
         verify_tag(&k, m, &ttr).unwrap()
     }
 
-    fn mock_path(
-        conn: &redis::Connection,
-        m: &[u8],
-        mut tmd: TraceMetadata,
-        len: u32,
-    ) -> TraceMetadata {
-        for i in 0..len {
-            tmd = mock_send(conn, m, &tmd, i, i + 1);
-        }
-        tmd
-    }
-
     #[test]
     fn tag_verifies() {
         let m = "Plaintext";
@@ -124,6 +323,17 @@ mod tests {
         assert_eq!(k, tmd1.ptr);
     }
 
+    #[test]
+    fn reader_tag_matches() {
+        let m: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let k = rand::random::<[u8; 16]>();
+        let tmd0 = new_message(&m);
+        let tts = generate_tag(&k, &m, &tmd0);
+        let streamed = generate_tag_from_reader(&k, &mut &m[..], &tmd0).unwrap();
+        assert_eq!(tts.addr, streamed.addr);
+        assert_eq!(tts.ct, streamed.ct);
+    }
+
     #[test]
     fn tag_fails() {
         let m1 = "Plaintext";
@@ -149,7 +359,7 @@ mod tests {
         let k = rand::random::<[u8; 16]>();
         let tmd0 = new_message(m.as_bytes());
         let tts = generate_tag(&k, m.as_bytes(), &tmd0);
-        let ttr = svr_process(&conn, &tts, 0, 1).unwrap();
+        let ttr = svr_process(&conn, &tts, &p(0), &p(1)).unwrap();
         let tmd1 = verify_tag(&k, m.as_bytes(), &ttr).unwrap();
         assert_eq!(k, tmd1.ptr);
 
@@ -171,8 +381,8 @@ mod tests {
             ct: [2; 16],
         };
 
-        let resp1 = svr_process(&conn, &tts1, 0, 1);
-        let resp2 = svr_process(&conn, &tts2, 0, 1);
+        let resp1 = svr_process(&conn, &tts1, &p(0), &p(1));
+        let resp2 = svr_process(&conn, &tts2, &p(0), &p(1));
         assert_eq!(resp1.unwrap().addr, tts1.addr);
         assert!(resp2.is_none());
 
@@ -192,15 +402,56 @@ mod tests {
         let tmd1 = mock_send(&conn, m.as_bytes(), &tmd0, 0, 1);
         let tmd2 = mock_send(&conn, m.as_bytes(), &tmd1, 1, 2);
 
-        let path = svr_trace(&conn, m.as_bytes(), &tmd2, 2);
-        assert_eq!(vec![2, 1, 0], path);
+        let path = svr_trace(&conn, m.as_bytes(), &tmd2, &p(2));
+        assert_eq!(ids(&[2, 1, 0]), path.path);
+        assert_eq!(StopReason::ReachedOrigin, path.stop);
+        assert_eq!(2, path.hop);
+        assert!(path.origin_confirmed);
 
-        let subpath = svr_trace(&conn, m.as_bytes(), &tmd1, 1);
-        assert_eq!(vec![1, 0], subpath);
+        let subpath = svr_trace(&conn, m.as_bytes(), &tmd1, &p(1));
+        assert_eq!(ids(&[1, 0]), subpath.path);
+
+        let origin = svr_trace(&conn, m.as_bytes(), &tmd0, &p(0));
+        assert_eq!(ids(&[0]), origin.path);
+        assert!(origin.origin_confirmed);
 
         let m2 = "Different Plaintext";
-        let wrong_msg_path = svr_trace(&conn, m2.as_bytes(), &tmd2, 2);
-        assert_eq!(vec![2], wrong_msg_path);
+        let wrong_msg_path = svr_trace(&conn, m2.as_bytes(), &tmd2, &p(2));
+        assert_eq!(ids(&[2]), wrong_msg_path.path);
+        assert_eq!(StopReason::MissingRecord, wrong_msg_path.stop);
+        assert!(!wrong_msg_path.origin_confirmed);
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn trace_processing_times() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let m = "Plaintext";
+        let tmd0 = new_message(m.as_bytes());
+        let mut tmd = tmd0;
+        let mut addrs = Vec::new();
+        for (i, ts) in [100, 200].iter().enumerate() {
+            let k = rand::random::<[u8; 16]>();
+            let tts = generate_tag(&k, m.as_bytes(), &tmd);
+            let ttr = svr_process_with_clock(
+                &conn,
+                &tts,
+                &p(i as u32),
+                &p(i as u32 + 1),
+                &FixedClock(*ts),
+            )
+            .unwrap();
+            addrs.insert(0, ttr.addr);
+            tmd = verify_tag(&k, m.as_bytes(), &ttr).unwrap();
+        }
+
+        let path = svr_trace(&conn, m.as_bytes(), &tmd, &p(2));
+        assert_eq!(ids(&[2, 1, 0]), path.path);
+        assert_eq!(vec![Some(200), Some(100)], path.processed_at);
+        assert_eq!(addrs, path.addrs);
 
         let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
     }
@@ -216,8 +467,10 @@ mod tests {
         let tmd1 = mock_send(&conn, m.as_bytes(), &tmd0, 0, 1);
         let tmd2 = mock_send(&conn, m2.as_bytes(), &tmd1, 1, 2);
 
-        let path = svr_trace(&conn, m2.as_bytes(), &tmd2, 2);
-        assert_eq!(vec![2, 1], path);
+        let path = svr_trace(&conn, m2.as_bytes(), &tmd2, &p(2));
+        assert_eq!(ids(&[2, 1]), path.path);
+        assert_eq!(StopReason::MissingRecord, path.stop);
+        assert_eq!(1, path.hop);
 
         let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
     }
@@ -232,56 +485,307 @@ mod tests {
         let tmd1 = mock_send(&conn, m.as_bytes(), &tmd0, 0, 1);
         let tmd2 = mock_send(&conn, m.as_bytes(), &tmd1, 3, 2);
 
-        let path = svr_trace(&conn, m.as_bytes(), &tmd2, 2);
-        assert_eq!(vec![2, 3], path);
+        let path = svr_trace(&conn, m.as_bytes(), &tmd2, &p(2));
+        assert_eq!(ids(&[2, 3]), path.path);
+        assert_eq!(StopReason::IdentityMismatch, path.stop);
+        assert_eq!(1, path.hop);
 
         let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
     }
 
-    #[bench]
-    fn bench_tag_gen(b: &mut Bencher) {
-        let m = [0u8; 256];
+    #[test]
+    fn trace_record_without_devices() {
+        let store = MemoryStore::new();
+        let m = b"Plaintext";
         let k = rand::random::<[u8; 16]>();
-        let md = TraceMetadata {
-            ptr: rand::random::<[u8; 16]>(),
-        };
-        b.iter(|| generate_tag(&k, &m, &md));
+        let ct = encipher(&k, &[0; 16]);
+        let record: [(&str, &[u8]); 4] = [("ct", &ct), ("sid", b"0"), ("rid", b"1"), ("ts", b"5")];
+        store.insert(&crprf(&k, m), &record);
+
+        let trace = svr_trace(&store, m, &TraceMetadata { ptr: k }, &p(1));
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+        assert_eq!(ids(&[1, 0]), trace.path);
     }
 
-    #[bench]
-    fn bench_tag_receive(b: &mut Bencher) {
-        let m = [0u8; 256];
+    #[test]
+    fn trace_device_hop() {
+        let store = MemoryStore::new();
+        let m = b"Plaintext";
+        let (phone, laptop) = (
+            Participant::new(1, b"phone"),
+            Participant::new(1, b"laptop"),
+        );
         let k = rand::random::<[u8; 16]>();
-        let ttr = RecTraceTag {
-            addr: crprf(&k, &m),
-        };
-        b.iter(|| verify_tag(&k, &m, &ttr));
+
+        // User 1 receives on their phone and forwards from their laptop
+        let tmd0 = new_message(m);
+        let ttr = svr_process(&store, &generate_tag(&k, m, &tmd0), &p(0), &phone).unwrap();
+        let tmd1 = verify_tag(&k, m, &ttr).unwrap();
+        let k = rand::random::<[u8; 16]>();
+        let ttr = svr_process(&store, &generate_tag(&k, m, &tmd1), &laptop, &p(2)).unwrap();
+        let tmd2 = verify_tag(&k, m, &ttr).unwrap();
+
+        let trace = svr_trace(&store, m, &tmd2, &p(2));
+        assert_eq!(vec![p(2), laptop, p(0)], trace.path);
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+        assert_eq!("1/6c6170746f70", trace.path[1].to_string());
+
+        let trace = svr_trace(&store, m, &tmd2, &Participant::new(3, b"laptop"));
+        assert_eq!(StopReason::IdentityMismatch, trace.stop);
+    }
+
+    #[test]
+    fn trace_opaque_ids() {
+        let store = MemoryStore::new();
+        let m = b"Plaintext";
+        let account = Participant::from(UserId(vec![0x9f; 16]));
+        let handle = Participant::new("anon-7c1e", b"");
+        let k = rand::random::<[u8; 16]>();
+
+        let tmd0 = new_message(m);
+        let ttr = svr_process(&store, &generate_tag(&k, m, &tmd0), &account, &handle).unwrap();
+        let tmd1 = verify_tag(&k, m, &ttr).unwrap();
+
+        let trace = svr_trace(&store, m, &tmd1, &handle);
+        assert_eq!(vec![handle.clone(), account.clone()], trace.path);
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+        assert_eq!("anon-7c1e", handle.to_string());
+        assert_eq!(format!("0x{}", "9f".repeat(16)), account.to_string());
+        assert_eq!("0xab", UserId(vec![0xab]).to_string());
+        assert_eq!("ab", UserId::from("ab").to_string());
+        assert_eq!("0x30786162", UserId::from("0xab").to_string());
+
+        let trace = svr_trace(&store, m, &tmd1, &UserId(b"anon-7c1E".to_vec()).into());
+        assert_eq!(StopReason::IdentityMismatch, trace.stop);
+
+        // An integer id is its decimal digits
+        assert_eq!(UserId::from("17"), UserId::from(17));
+        assert_ne!(UserId::from(17), UserId(vec![17]));
     }
 
-    #[bench]
-    fn bench_tag_process(b: &mut Bencher) {
+    #[test]
+    fn process_group() {
         let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
         let conn = client.get_connection().unwrap();
-        let sid = rand::random::<u32>();
-        let rid = rand::random::<u32>();
-        let tts = SenderTraceTag {
-            addr: rand::random::<[u8; 32]>(),
-            ct: rand::random::<[u8; 16]>(),
-        };
-        b.iter(|| {
-            svr_process(&conn, &tts, sid, rid);
-            let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
-        });
+
+        let m = "Plaintext";
+        let tmd0 = new_message(m.as_bytes());
+        let ks: Vec<[u8; 16]> = (0..3).map(|_| rand::random()).collect();
+        let tts = generate_group_tags(&ks, m.as_bytes(), &tmd0);
+        let ttrs =
+            svr_process_group_with_clock(&conn, &tts, &p(0), &ids(&[1, 2, 3]), &FixedClock(300))
+                .unwrap();
+        for (i, (k, ttr)) in ks.iter().zip(&ttrs).enumerate() {
+            let tmd = verify_tag(k, m.as_bytes(), ttr).unwrap();
+            let rid = i as u32 + 1;
+            let path = svr_trace(&conn, m.as_bytes(), &tmd, &p(rid));
+            assert_eq!(ids(&[rid, 0]), path.path);
+            assert_eq!(vec![Some(300)], path.processed_at);
+        }
+
+        // A group containing an already processed tag stores nothing
+        let k = rand::random::<[u8; 16]>();
+        let mut tts = generate_group_tags(&[k], m.as_bytes(), &tmd0);
+        tts.push(generate_tag(&ks[0], m.as_bytes(), &tmd0));
+        assert!(svr_process_group(&conn, &tts, &p(0), &ids(&[4, 1])).is_none());
+        assert!(!Store::exists(&conn, &tts[0].addr));
+        assert!(svr_process_group(&conn, &tts[..1], &p(0), &ids(&[4, 5])).is_none());
+        assert!(svr_process_group(&conn, &[], &p(0), &[]).is_none());
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
     }
 
-    #[bench]
-    fn bench_trace_path(b: &mut Bencher) {
-        let len = 10;
+    #[test]
+    fn process_batch() {
         let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
         let conn = client.get_connection().unwrap();
-        let m = [0u8; 256];
-        let tmd = mock_path(&conn, &m, new_message(&m), len);
-        b.iter(|| svr_trace(&conn, &m, &tmd, len));
+
+        let m = "Plaintext";
+        let tmd0 = new_message(m.as_bytes());
+        let ks: Vec<[u8; 16]> = (0..3).map(|_| rand::random()).collect();
+        svr_process(
+            &conn,
+            &generate_tag(&ks[1], m.as_bytes(), &tmd0),
+            &p(0),
+            &p(2),
+        )
+        .unwrap();
+
+        // The second tag was already processed and the fourth repeats the third
+        let items: Vec<(SenderTraceTag, Participant, Participant)> = [0, 1, 2, 2]
+            .iter()
+            .enumerate()
+            .map(|(i, &k)| {
+                (
+                    generate_tag(&ks[k], m.as_bytes(), &tmd0),
+                    p(0),
+                    p(i as u32 + 1),
+                )
+            })
+            .collect();
+        let ttrs = svr_process_batch_with_clock(&conn, &items, &FixedClock(400));
+        let stored: Vec<bool> = ttrs.iter().map(|ttr| ttr.is_some()).collect();
+        assert_eq!(vec![true, false, true, false], stored);
+
+        for (k, rid) in [(0, 1), (2, 3)].iter() {
+            let ttr = ttrs[*rid as usize - 1].as_ref().unwrap();
+            let tmd = verify_tag(&ks[*k], m.as_bytes(), ttr).unwrap();
+            let path = svr_trace(&conn, m.as_bytes(), &tmd, &p(*rid));
+            assert_eq!(ids(&[*rid, 0]), path.path);
+            assert_eq!(vec![Some(400)], path.processed_at);
+        }
+        assert!(svr_process_batch(&conn, &[]).is_empty());
+
         let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
     }
+
+    #[test]
+    fn trace_malformed_record() {
+        let store = MemoryStore::new();
+        let m = b"Plaintext";
+        let (k1, k2) = (rand::random::<[u8; 16]>(), rand::random::<[u8; 16]>());
+        store.insert(
+            &crprf(&k1, m),
+            &[("ct", &[0; 8]), ("sid", b"0"), ("rid", b"1")],
+        );
+        store.insert(&crprf(&k2, m), &[("ct", &[0; 16]), ("rid", b"1")]);
+
+        for k in [k1, k2].iter() {
+            let path = svr_trace(&store, m, &TraceMetadata { ptr: *k }, &p(1));
+            assert_eq!(ids(&[1]), path.path);
+            assert_eq!(StopReason::MalformedRecord, path.stop);
+            assert!(!path.origin_confirmed);
+        }
+    }
+
+    #[derive(Clone, Debug)]
+    enum Fault {
+        Honest,
+        Switch,
+        ForgedSender,
+    }
+
+    fn forwards() -> impl Strategy<Value = Vec<(Index, u32, Fault)>> {
+        let fault = prop_oneof![
+            6 => Just(Fault::Honest),
+            1 => Just(Fault::Switch),
+            1 => Just(Fault::ForgedSender),
+        ];
+        prop::collection::vec((any::<Index>(), 0..6u32, fault), 0..16)
+    }
+
+    // Ground truth for a forwarding tree: node 0 originates the message and node `i > 0` received
+    // `msg[i]` from `parent[i]`, recorded with sender id `sid[i]`. A tampered node forwards with a
+    // pointer other than the one it received.
+    struct Cascade {
+        uid: Vec<u32>,
+        parent: Vec<usize>,
+        sid: Vec<u32>,
+        msg: Vec<Vec<u8>>,
+        tampered: Vec<bool>,
+        md: Vec<TraceMetadata>,
+    }
+
+    fn mock_cascade(
+        conn: &impl Store,
+        forwards: &[(Index, u32, Fault)],
+        tampered: &[bool],
+    ) -> Cascade {
+        let m = b"Plaintext".to_vec();
+        let mut c = Cascade {
+            uid: vec![0],
+            parent: vec![0],
+            sid: vec![0],
+            tampered: vec![tampered[0]],
+            md: vec![new_message(&m)],
+            msg: vec![m],
+        };
+        for (i, (parent, uid, fault)) in forwards.iter().enumerate() {
+            let i = i + 1;
+            let p = parent.index(i);
+            let msg = match fault {
+                Fault::Switch => format!("Switch {}", i).into_bytes(),
+                _ => c.msg[p].clone(),
+            };
+            let sid = match fault {
+                Fault::ForgedSender => 1000 + i as u32,
+                _ => c.uid[p],
+            };
+            let mut md = c.md[p].clone();
+            if c.tampered[p] {
+                md.ptr = rand::random();
+            }
+
+            c.md.push(mock_send(conn, &msg, &md, sid, *uid));
+            c.uid.push(*uid);
+            c.parent.push(p);
+            c.sid.push(sid);
+            c.msg.push(msg);
+            c.tampered.push(tampered[i]);
+        }
+        c
+    }
+
+    // Mirrors the checks `svr_trace` makes on each stored record using the cascade's ground truth.
+    fn expected_trace(c: &Cascade, v: usize) -> (Vec<u32>, StopReason) {
+        let mut path = vec![c.uid[v]];
+        let mut x = Some(v);
+        let stop = loop {
+            let node = match x {
+                Some(0) => break StopReason::ReachedOrigin,
+                Some(node) if c.msg[node] == c.msg[v] => node,
+                _ => break StopReason::MissingRecord,
+            };
+            if *path.last().unwrap() != c.uid[node] {
+                break StopReason::IdentityMismatch;
+            }
+            path.push(c.sid[node]);
+            let p = c.parent[node];
+            x = if c.tampered[p] { None } else { Some(p) };
+        };
+        (path, stop)
+    }
+
+    proptest! {
+        #[test]
+        fn honest_chain_traces_exactly(uids in prop::collection::vec(any::<u32>(), 1..32)) {
+            let store = MemoryStore::new();
+            let m = b"Plaintext";
+            let mut tmd = new_message(m);
+            for pair in uids.windows(2) {
+                tmd = mock_send(&store, m, &tmd, pair[0], pair[1]);
+            }
+
+            let trace = svr_trace(&store, m, &tmd, &p(*uids.last().unwrap()));
+            let chain: Vec<u32> = uids.iter().rev().cloned().collect();
+            prop_assert_eq!(ids(&chain), trace.path);
+            prop_assert_eq!(uids.len() - 1, trace.hop);
+            prop_assert_eq!(StopReason::ReachedOrigin, trace.stop);
+            prop_assert!(trace.origin_confirmed);
+        }
+
+        #[test]
+        fn trace_matches_forwarding_model(
+            forwards in forwards(),
+            tampered in prop::collection::vec(prop::bool::weighted(0.1), 17),
+            ptr in any::<[u8; 16]>(),
+        ) {
+            let store = MemoryStore::new();
+            let c = mock_cascade(&store, &forwards, &tampered);
+
+            for v in 0..c.uid.len() {
+                let trace = svr_trace(&store, &c.msg[v], &c.md[v], &p(c.uid[v]));
+                let (path, stop) = expected_trace(&c, v);
+                prop_assert_eq!(path.len() - 1, trace.hop);
+                prop_assert_eq!(ids(&path), trace.path);
+                prop_assert_eq!(stop, trace.stop);
+                prop_assert_eq!(stop == StopReason::ReachedOrigin, trace.origin_confirmed);
+            }
+
+            // Metadata not issued by the platform points at no record
+            let trace = svr_trace(&store, &c.msg[0], &TraceMetadata { ptr }, &p(7));
+            prop_assert_eq!(ids(&[7]), trace.path);
+            prop_assert_eq!(StopReason::MissingRecord, trace.stop);
+        }
+    }
 }
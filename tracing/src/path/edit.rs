@@ -0,0 +1,295 @@
+use super::{RecTraceTag, TraceMetadata};
+use crate::store::{read_array, read_int, Store};
+use crate::{crprf, decipher, encipher, prf, Clock, Participant, StopReason, SystemClock};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+
+// Path traceback that follows a message across edits. Addresses are computed over the digest of
+// the message rather than the message itself, and a forwarding client that edits a message it
+// received attests to the digest of the parent, encrypted under its key. The trace switches to the
+// parent digest at such a record and continues only if the sender really received the parent, so
+// a false attestation ends the trace as a missing record.
+
+#[derive(Serialize, Deserialize)]
+pub struct SenderTraceTag {
+    addr: [u8; 32],
+    ct: [u8; 16],
+    ct_parent: Option<[u8; 32]>,
+}
+
+// The forward from `path[hop + 1]` to `path[hop]` carried an edit of the message with digest
+// `parent`.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct EditPoint {
+    pub hop: usize,
+    pub parent: [u8; 32],
+}
+
+// As `path::TraceOutcome`, with the edit points the trace followed.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceOutcome {
+    pub path: Vec<Participant>,
+    pub processed_at: Vec<Option<u64>>,
+    pub addrs: Vec<[u8; 32]>,
+    pub edits: Vec<EditPoint>,
+    pub stop: StopReason,
+    pub hop: usize,
+    pub origin_confirmed: bool,
+}
+
+pub fn digest(m: &[u8]) -> [u8; 32] {
+    let mut y: [u8; 32] = Default::default();
+    y.copy_from_slice(Sha3_256::digest(m).as_slice());
+    y
+}
+
+pub fn new_message(m: &[u8]) -> TraceMetadata {
+    super::new_message(m)
+}
+
+pub fn generate_tag(k: &[u8; 16], m: &[u8], md: &TraceMetadata) -> SenderTraceTag {
+    SenderTraceTag {
+        addr: crprf(k, &digest(m)),
+        ct: encipher(k, &md.ptr),
+        ct_parent: None,
+    }
+}
+
+// Tags `m` as an edit of `parent`, the message the sender received with `md`.
+pub fn generate_edit_tag(
+    k: &[u8; 16],
+    m: &[u8],
+    parent: &[u8],
+    md: &TraceMetadata,
+) -> SenderTraceTag {
+    SenderTraceTag {
+        ct_parent: Some(crypt_digest(k, &digest(parent), encipher)),
+        ..generate_tag(k, m, md)
+    }
+}
+
+pub fn verify_tag(k: &[u8; 16], m: &[u8], ttr: &RecTraceTag) -> Option<TraceMetadata> {
+    super::verify_tag(k, &digest(m), ttr)
+}
+
+pub fn svr_process(
+    conn: &impl Store,
+    tts: &SenderTraceTag,
+    sid: &Participant,
+    rid: &Participant,
+) -> Option<RecTraceTag> {
+    svr_process_with_clock(conn, tts, sid, rid, &SystemClock)
+}
+
+pub fn svr_process_with_clock(
+    conn: &impl Store,
+    tts: &SenderTraceTag,
+    sid: &Participant,
+    rid: &Participant,
+    clock: &impl Clock,
+) -> Option<RecTraceTag> {
+    let ts = clock.now().to_string();
+    let mut record: Vec<(&str, &[u8])> = vec![
+        ("ct", &tts.ct[..]),
+        ("sid", sid.user.as_bytes()),
+        ("sid_device", &sid.device[..]),
+        ("rid", rid.user.as_bytes()),
+        ("rid_device", &rid.device[..]),
+        ("ts", ts.as_bytes()),
+    ];
+    if let Some(ct_parent) = &tts.ct_parent {
+        record.push(("ct_parent", &ct_parent[..]));
+    }
+    if conn.insert(&tts.addr, &record) {
+        Some(RecTraceTag { addr: tts.addr })
+    } else {
+        None
+    }
+}
+
+pub fn svr_trace(
+    conn: &impl Store,
+    m: &[u8],
+    md: &TraceMetadata,
+    uid: &Participant,
+) -> TraceOutcome {
+    let mut processed_at = Vec::new();
+    let mut addrs = Vec::new();
+    let mut edits = Vec::new();
+    let walk = super::walk_back(
+        conn,
+        &digest(m),
+        &md.ptr,
+        uid,
+        usize::MAX,
+        |addr, ptr, record| {
+            let parent = match record.get("ct_parent") {
+                Some(_) => match read_array(record, "ct_parent") {
+                    Some(ct_parent) => Some(crypt_digest(ptr, &ct_parent, decipher)),
+                    None => return Err(StopReason::MalformedRecord),
+                },
+                None => None,
+            };
+            if let Some(parent) = parent {
+                edits.push(EditPoint {
+                    hop: addrs.len(),
+                    parent,
+                });
+            }
+            processed_at.push(read_int(record, "ts"));
+            addrs.push(*addr);
+            Ok(parent.map(|d| d.to_vec()))
+        },
+    );
+    let stop = walk.stop.unwrap();
+    TraceOutcome {
+        hop: walk.path.len() - 1,
+        path: walk.path,
+        processed_at,
+        addrs,
+        edits,
+        stop,
+        origin_confirmed: stop == StopReason::ReachedOrigin,
+    }
+}
+
+// Applies `f` blockwise to a digest under a key derived from the forward's key.
+fn crypt_digest(k: &[u8; 16], x: &[u8; 32], f: fn(&[u8; 16], &[u8; 16]) -> [u8; 16]) -> [u8; 32] {
+    let ek = prf(k, b"edit");
+    let (mut lo, mut hi): ([u8; 16], [u8; 16]) = Default::default();
+    lo.copy_from_slice(&x[..16]);
+    hi.copy_from_slice(&x[16..]);
+    let mut y = [0; 32];
+    y[..16].copy_from_slice(&f(&ek, &lo));
+    y[16..].copy_from_slice(&f(&ek, &hi));
+    y
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canon::Canonicalizer;
+    use crate::retention::Visited;
+    use crate::store::MemoryStore;
+
+    fn p(uid: u32) -> Participant {
+        Participant::from(uid)
+    }
+
+    fn ids(uids: &[u32]) -> Vec<Participant> {
+        uids.iter().map(|&uid| Participant::from(uid)).collect()
+    }
+
+    fn mock_send(
+        conn: &impl Store,
+        m: &[u8],
+        parent: Option<&[u8]>,
+        tmd: &TraceMetadata,
+        sid: u32,
+        rid: u32,
+    ) -> TraceMetadata {
+        let k = rand::random::<[u8; 16]>();
+        let tts = match parent {
+            Some(parent) => generate_edit_tag(&k, m, parent, tmd),
+            None => generate_tag(&k, m, tmd),
+        };
+        let ttr = svr_process(conn, &tts, &p(sid), &p(rid)).unwrap();
+        verify_tag(&k, m, &ttr).unwrap()
+    }
+
+    #[test]
+    fn trace_across_edits() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let m = "Plaintext".as_bytes();
+        let m2 = "Different Plaintext".as_bytes();
+        let tmd0 = new_message(m);
+        let tmd1 = mock_send(&conn, m, None, &tmd0, 0, 1);
+        let tmd2 = mock_send(&conn, m2, Some(m), &tmd1, 1, 2);
+        let tmd3 = mock_send(&conn, m2, None, &tmd2, 2, 3);
+
+        let trace = svr_trace(&conn, m2, &tmd3, &p(3));
+        assert_eq!(ids(&[3, 2, 1, 0]), trace.path);
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+        assert_eq!(3, trace.visited().len());
+        assert!(trace.addrs.iter().all(|addr| conn.exists(addr)));
+        assert_eq!(
+            vec![EditPoint {
+                hop: 1,
+                parent: digest(m)
+            }],
+            trace.edits
+        );
+
+        // User 3 claims an edit of a message it never received
+        let tmd4 = mock_send(&conn, m, Some(b"Other Plaintext"), &tmd3, 3, 4);
+        let trace = svr_trace(&conn, m, &tmd4, &p(4));
+        assert_eq!(ids(&[4, 3]), trace.path);
+        assert_eq!(StopReason::MissingRecord, trace.stop);
+        assert_eq!(1, trace.edits.len());
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn trace_canonical_message() {
+        let store = MemoryStore::new();
+        let canon = Canonicalizer::default();
+        let m = canon.canonicalize("Plain  text \u{2764}\u{fe0f}".as_bytes());
+        let m2 = canon.canonicalize(" Plain text\n\u{2764}".as_bytes());
+        let tmd0 = new_message(&m);
+        let tmd1 = mock_send(&store, &m, None, &tmd0, 0, 1);
+        let tmd2 = mock_send(&store, &m2, None, &tmd1, 1, 2);
+
+        let trace = svr_trace(&store, &m2, &tmd2, &p(2));
+        assert_eq!(ids(&[2, 1, 0]), trace.path);
+        assert!(trace.edits.is_empty());
+    }
+
+    #[test]
+    fn edit_processing_times() {
+        struct FixedClock(u64);
+
+        impl Clock for FixedClock {
+            fn now(&self) -> u64 {
+                self.0
+            }
+        }
+
+        let store = MemoryStore::new();
+        let (m, m2) = (b"Plaintext", b"Edited plaintext");
+        let tmd0 = new_message(m);
+        let tmd1 = mock_send(&store, m, None, &tmd0, 0, 1);
+        let k = rand::random::<[u8; 16]>();
+        let tts = generate_edit_tag(&k, m2, m, &tmd1);
+        let ttr = svr_process_with_clock(&store, &tts, &p(1), &p(2), &FixedClock(800)).unwrap();
+        let tmd2 = verify_tag(&k, m2, &ttr).unwrap();
+
+        let trace = svr_trace(&store, m2, &tmd2, &p(2));
+        assert_eq!(Some(800), trace.processed_at[0]);
+        assert_eq!(1, trace.edits.len());
+    }
+
+    #[test]
+    fn trace_malformed_attestation() {
+        let store = MemoryStore::new();
+        let (m, m2) = (b"Plaintext", b"Edited plaintext");
+        let tmd0 = new_message(m);
+        let tmd1 = mock_send(&store, m, None, &tmd0, 0, 1);
+        let k = rand::random::<[u8; 16]>();
+        let tts = generate_tag(&k, m2, &tmd1);
+        let record: Vec<(&str, &[u8])> = vec![
+            ("ct", &tts.ct[..]),
+            ("sid", b"1"),
+            ("rid", b"2"),
+            ("ct_parent", b"short"),
+        ];
+        assert!(store.insert(&tts.addr, &record));
+
+        let trace = svr_trace(&store, m2, &TraceMetadata { ptr: k }, &p(2));
+        assert_eq!(ids(&[2]), trace.path);
+        assert_eq!(StopReason::MalformedRecord, trace.stop);
+        assert!(trace.addrs.is_empty());
+    }
+}
@@ -0,0 +1,432 @@
+use crate::store::{read_array, read_int, read_participant, Record, Store, Tampered};
+use crate::{
+    crprf, crprf_finish, crprf_mac, decipher, encipher, prf, read_chunks, Clock, Participant,
+    StopReason, SystemClock,
+};
+use hmac::Mac;
+use rand::rngs::OsRng;
+use rand::{CryptoRng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
+
+// Path traceback with forward links. Besides the path record, every forward stores a link record
+// at an address derived from the sender's forward key `fk` and a per-sender counter, holding the
+// recipient's key. A recipient's forward key is derived from its key and randomness chosen by the
+// server, so its sender cannot predict or squat its links.
+//
+// Every path record also keeps the sender's forward key encrypted under the record's pointer. It
+// is only needed for the origin, whose forward key is not derived from a record it received, so
+// that forward traversal can start there; storing it for every forward keeps origin sends
+// indistinguishable to the server.
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TraceMetadata {
+    ptr: [u8; 16],
+    fk: [u8; 16],
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SenderTraceTag {
+    addr: [u8; 32],
+    ct: [u8; 16],
+    link: [u8; 32],
+    ct_k: [u8; 16],
+    ct_fk: [u8; 16],
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RecTraceTag {
+    addr: [u8; 32],
+    ks: [u8; 16],
+}
+
+// `ctr` is the sender's counter for the forward. An edge is wellformed when the recipient's record
+// points back to the pointer under which the sender received the message.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ForwardEdge {
+    pub ctr: u32,
+    pub wellformed: bool,
+    pub processed_at: Option<u64>,
+    pub child: ForwardTree,
+}
+
+// `truncated` is set on nodes at the depth bound that forwarded the message further, and
+// `tampered` on nodes whose forwards stopped at a record that failed its integrity check.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ForwardTree {
+    pub uid: Participant,
+    pub edges: Vec<ForwardEdge>,
+    pub truncated: bool,
+    pub tampered: bool,
+}
+
+pub fn new_message(m: &[u8]) -> TraceMetadata {
+    new_message_with_rng(m, &mut OsRng::new().unwrap())
+}
+
+pub fn new_message_with_rng(_m: &[u8], rng: &mut (impl RngCore + CryptoRng)) -> TraceMetadata {
+    TraceMetadata {
+        ptr: [0; 16],
+        fk: rng.gen::<[u8; 16]>(),
+    }
+}
+
+pub fn generate_tag(k: &[u8; 16], m: &[u8], md: &TraceMetadata, ctr: u32) -> SenderTraceTag {
+    let lptr = prf(&md.fk, &ctr.to_be_bytes());
+    SenderTraceTag {
+        addr: crprf(k, m),
+        ct: encipher(k, &md.ptr),
+        link: crprf(&lptr, m),
+        ct_k: encipher(&lptr, k),
+        ct_fk: encipher(k, &md.fk),
+    }
+}
+
+// As `generate_tag`, reading the message from `r` to its end instead of from memory.
+pub fn generate_tag_from_reader(
+    k: &[u8; 16],
+    r: &mut impl Read,
+    md: &TraceMetadata,
+    ctr: u32,
+) -> io::Result<SenderTraceTag> {
+    let lptr = prf(&md.fk, &ctr.to_be_bytes());
+    let (mut addr, mut link) = (crprf_mac(k), crprf_mac(&lptr));
+    read_chunks(r, |x| {
+        addr.input(x);
+        link.input(x);
+    })?;
+    Ok(SenderTraceTag {
+        addr: crprf_finish(addr),
+        ct: encipher(k, &md.ptr),
+        link: crprf_finish(link),
+        ct_k: encipher(&lptr, k),
+        ct_fk: encipher(k, &md.fk),
+    })
+}
+
+pub fn verify_tag(k: &[u8; 16], m: &[u8], ttr: &RecTraceTag) -> Option<TraceMetadata> {
+    if crprf(k, m) != ttr.addr {
+        None
+    } else {
+        Some(TraceMetadata {
+            ptr: *k,
+            fk: prf(k, &ttr.ks),
+        })
+    }
+}
+
+pub fn svr_process(
+    conn: &impl Store,
+    tts: &SenderTraceTag,
+    sid: &Participant,
+    rid: &Participant,
+) -> Option<RecTraceTag> {
+    svr_process_with_rng(conn, tts, sid, rid, &mut OsRng::new().unwrap())
+}
+
+// The path record and the link record are stored together or not at all, so a reused counter
+// fails the forward just as a reused key does.
+pub fn svr_process_with_rng(
+    conn: &impl Store,
+    tts: &SenderTraceTag,
+    sid: &Participant,
+    rid: &Participant,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Option<RecTraceTag> {
+    svr_process_with_clock(conn, tts, sid, rid, &SystemClock, rng)
+}
+
+pub fn svr_process_with_clock(
+    conn: &impl Store,
+    tts: &SenderTraceTag,
+    sid: &Participant,
+    rid: &Participant,
+    clock: &impl Clock,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Option<RecTraceTag> {
+    let ks = rng.gen::<[u8; 16]>();
+    let ts = clock.now().to_string();
+    let record: [(&str, &[u8]); 8] = [
+        ("ct", &tts.ct[..]),
+        ("sid", sid.user.as_bytes()),
+        ("sid_device", &sid.device[..]),
+        ("rid", rid.user.as_bytes()),
+        ("rid_device", &rid.device[..]),
+        ("ts", ts.as_bytes()),
+        ("ks", &ks[..]),
+        ("ct_fk", &tts.ct_fk[..]),
+    ];
+    let link: [(&str, &[u8]); 1] = [("ct_k", &tts.ct_k[..])];
+    if conn.insert_all(&[(&tts.addr[..], &record[..]), (&tts.link[..], &link[..])]) {
+        Some(RecTraceTag { addr: tts.addr, ks })
+    } else {
+        None
+    }
+}
+
+// Path records are laid out as in the path scheme, so the backward trace is the same.
+pub fn svr_trace(
+    conn: &impl Store,
+    m: &[u8],
+    md: &TraceMetadata,
+    uid: &Participant,
+) -> super::TraceOutcome {
+    super::svr_trace(conn, m, &super::TraceMetadata { ptr: md.ptr }, uid)
+}
+
+// Walks back `hop` forwards from the reporter `uid` and returns the forwards made from the user
+// reached there, down to `depth` levels. Fails with the reason the backward walk stopped early,
+// including reaching the origin before `hop` forwards. Forwards from an origin stored before the
+// origin's forward key was kept fail with `MissingRecord`.
+pub fn svr_trace_forward(
+    conn: &impl Store,
+    m: &[u8],
+    md: &TraceMetadata,
+    uid: &Participant,
+    hop: usize,
+    depth: usize,
+) -> Result<ForwardTree, StopReason> {
+    if hop == 0 {
+        return Ok(svr_build_tree(conn, m, &md.ptr, &md.fk, uid, depth));
+    }
+    let mut last = None;
+    let walk = super::walk_back(conn, m, &md.ptr, uid, hop, |_, ptr, record| {
+        last = Some((*ptr, record.clone()));
+        Ok(None)
+    });
+    if let Some(stop) = walk.stop {
+        return Err(stop);
+    }
+    let (uid, ptr) = (walk.path.last().unwrap(), walk.ptr);
+    let fk = if ptr == [0; 16] {
+        // The origin's forward key is kept in the record of the forward walked last
+        let (last_ptr, record) = last.unwrap();
+        let ct_fk = read_array(&record, "ct_fk").ok_or(StopReason::MissingRecord)?;
+        decipher(&last_ptr, &ct_fk)
+    } else {
+        let record = read_record(conn, &crprf(&ptr, m))?;
+        forward_key(&record, &ptr).ok_or(StopReason::MalformedRecord)?
+    };
+    Ok(svr_build_tree(conn, m, &ptr, &fk, uid, depth))
+}
+
+fn read_record(conn: &impl Store, addr: &[u8]) -> Result<Record, StopReason> {
+    match conn.get_checked(addr) {
+        Ok(Some(record)) => Ok(record),
+        Ok(None) => Err(StopReason::MissingRecord),
+        Err(Tampered) => Err(StopReason::TamperedRecord),
+    }
+}
+
+fn forward_key(record: &Record, ptr: &[u8; 16]) -> Option<[u8; 16]> {
+    read_array::<16>(record, "ks").map(|ks| prf(ptr, &ks))
+}
+
+// Follows the links under `fk` by counter until one is missing or leads to a record sent by
+// someone other than `uid`.
+fn svr_build_tree(
+    conn: &impl Store,
+    m: &[u8],
+    ptr: &[u8; 16],
+    fk: &[u8; 16],
+    uid: &Participant,
+    depth: usize,
+) -> ForwardTree {
+    let mut tree = ForwardTree {
+        uid: uid.clone(),
+        edges: Vec::new(),
+        truncated: false,
+        tampered: false,
+    };
+    let mut ctr: u32 = 0;
+    loop {
+        let lptr = prf(fk, &ctr.to_be_bytes());
+        let ct_k = match read_record(conn, &crprf(&lptr, m)) {
+            Ok(link) => match read_array(&link, "ct_k") {
+                Some(ct_k) => ct_k,
+                None => break,
+            },
+            Err(stop) => {
+                tree.tampered = stop == StopReason::TamperedRecord;
+                break;
+            }
+        };
+        if depth == 0 {
+            tree.truncated = true;
+            break;
+        }
+
+        let k = decipher(&lptr, &ct_k);
+        let record = match read_record(conn, &crprf(&k, m)) {
+            Ok(record) => record,
+            Err(stop) => {
+                tree.tampered = stop == StopReason::TamperedRecord;
+                break;
+            }
+        };
+        let (ct, sid, rid, child_fk) = match (
+            read_array(&record, "ct"),
+            read_participant(&record, "sid"),
+            read_participant(&record, "rid"),
+            forward_key(&record, &k),
+        ) {
+            (Some(ct), Some(sid), Some(rid), Some(child_fk)) => (ct, sid, rid, child_fk),
+            _ => break,
+        };
+        if !sid.same_user(uid) {
+            break;
+        }
+
+        tree.edges.push(ForwardEdge {
+            ctr,
+            wellformed: decipher(&k, &ct) == *ptr,
+            processed_at: read_int(&record, "ts"),
+            child: svr_build_tree(conn, m, &k, &child_fk, &rid, depth - 1),
+        });
+        ctr += 1;
+    }
+    tree
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn p(uid: u32) -> Participant {
+        Participant::from(uid)
+    }
+
+    fn ids(uids: &[u32]) -> Vec<Participant> {
+        uids.iter().map(|&uid| Participant::from(uid)).collect()
+    }
+
+    fn mock_send(
+        conn: &impl Store,
+        m: &[u8],
+        tmd: &TraceMetadata,
+        ctr: u32,
+        sid: u32,
+        rid: u32,
+    ) -> TraceMetadata {
+        let k = rand::random::<[u8; 16]>();
+        let tts = generate_tag(&k, m, tmd, ctr);
+        let ttr = svr_process(conn, &tts, &p(sid), &p(rid)).unwrap();
+        verify_tag(&k, m, &ttr).unwrap()
+    }
+
+    fn uids(tree: &ForwardTree) -> Vec<Participant> {
+        tree.edges.iter().map(|e| e.child.uid.clone()).collect()
+    }
+
+    #[test]
+    fn reader_tag_matches() {
+        let m: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let k = rand::random::<[u8; 16]>();
+        let tmd0 = new_message(&m);
+        let tts = generate_tag(&k, &m, &tmd0, 2);
+        let streamed = generate_tag_from_reader(&k, &mut &m[..], &tmd0, 2).unwrap();
+        assert_eq!(
+            serde_json::to_string(&tts).unwrap(),
+            serde_json::to_string(&streamed).unwrap()
+        );
+    }
+
+    #[test]
+    fn trace_forward() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let m = "Plaintext".as_bytes();
+        let tmd0 = new_message(m);
+        let tmd1 = mock_send(&conn, m, &tmd0, 0, 0, 1);
+        let tmd2 = mock_send(&conn, m, &tmd1, 0, 1, 2);
+        mock_send(&conn, m, &tmd1, 1, 1, 3);
+        let tmd4 = mock_send(&conn, m, &tmd2, 0, 2, 4);
+
+        let tree = svr_trace_forward(&conn, m, &tmd1, &p(1), 0, 2).unwrap();
+        assert_eq!(ids(&[2, 3]), uids(&tree));
+        assert_eq!(ids(&[4]), uids(&tree.edges[0].child));
+        assert!(tree.edges.iter().all(|e| e.wellformed));
+        assert!(!tree.edges[0].child.truncated);
+
+        let shallow = svr_trace_forward(&conn, m, &tmd1, &p(1), 0, 1).unwrap();
+        assert!(shallow.edges[0].child.truncated);
+        assert!(!shallow.edges[1].child.truncated);
+
+        assert_eq!(Ok(tree), svr_trace_forward(&conn, m, &tmd4, &p(4), 2, 2));
+
+        // The origin's forwards are found from its forward key kept in the first record
+        let origin = svr_trace_forward(&conn, m, &tmd4, &p(4), 3, 2).unwrap();
+        assert_eq!(p(0), origin.uid);
+        assert_eq!(ids(&[1]), uids(&origin));
+        assert!(origin.edges[0].wellformed);
+        assert_eq!(ids(&[2, 3]), uids(&origin.edges[0].child));
+        assert_eq!(
+            Err(StopReason::ReachedOrigin),
+            svr_trace_forward(&conn, m, &tmd4, &p(4), 4, 2)
+        );
+        assert_eq!(ids(&[4, 2, 1, 0]), svr_trace(&conn, m, &tmd4, &p(4)).path);
+
+        // A reused counter collides with the existing link
+        let tts = generate_tag(&rand::random::<[u8; 16]>(), m, &tmd1, 0);
+        assert!(svr_process(&conn, &tts, &p(1), &p(5)).is_none());
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn trace_forward_forged_link() {
+        let store = MemoryStore::new();
+        let m = "Plaintext".as_bytes();
+        let tmd0 = new_message(m);
+        let tmd1 = mock_send(&store, m, &tmd0, 0, 0, 1);
+        let tmd2 = mock_send(&store, m, &tmd1, 0, 1, 2);
+        let tmd3 = mock_send(&store, m, &tmd0, 1, 0, 3);
+
+        // User 2 links its next forward to the record of its own receipt
+        let k = rand::random::<[u8; 16]>();
+        let mut tts = generate_tag(&k, m, &tmd2, 0);
+        tts.ct_k = encipher(&prf(&tmd2.fk, &0u32.to_be_bytes()), &tmd2.ptr);
+        svr_process(&store, &tts, &p(2), &p(4)).unwrap();
+        let tree = svr_trace_forward(&store, m, &tmd2, &p(2), 0, 1).unwrap();
+        assert!(tree.edges.is_empty());
+
+        // User 3 sends a record that points back to the wrong pointer
+        let k = rand::random::<[u8; 16]>();
+        let mut tts = generate_tag(&k, m, &tmd3, 0);
+        tts.ct = encipher(&k, &[1; 16]);
+        let ttr = svr_process(&store, &tts, &p(3), &p(5)).unwrap();
+        let tmd5 = verify_tag(&k, m, &ttr).unwrap();
+        let tree = svr_trace_forward(&store, m, &tmd3, &p(3), 0, 1).unwrap();
+        assert_eq!(ids(&[5]), uids(&tree));
+        assert!(!tree.edges[0].wellformed);
+        assert_eq!(
+            Err(StopReason::MissingRecord),
+            svr_trace_forward(&store, m, &tmd5, &p(5), 2, 1)
+        );
+    }
+
+    #[test]
+    fn forward_processing_times() {
+        struct FixedClock(u64);
+
+        impl Clock for FixedClock {
+            fn now(&self) -> u64 {
+                self.0
+            }
+        }
+
+        let store = MemoryStore::new();
+        let m = "Plaintext".as_bytes();
+        let tmd0 = new_message(m);
+        let tmd1 = mock_send(&store, m, &tmd0, 0, 0, 1);
+        let k = rand::random::<[u8; 16]>();
+        let tts = generate_tag(&k, m, &tmd1, 0);
+        let mut rng = rand::thread_rng();
+        svr_process_with_clock(&store, &tts, &p(1), &p(2), &FixedClock(700), &mut rng).unwrap();
+
+        let tree = svr_trace_forward(&store, m, &tmd1, &p(1), 0, 1).unwrap();
+        assert_eq!(Some(700), tree.edges[0].processed_at);
+    }
+}
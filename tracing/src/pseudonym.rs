@@ -0,0 +1,292 @@
+use crate::keyring::Keyring;
+use crate::prf;
+use crate::store::{decode_fields, encode_fields, Entry, Record, Store, Tampered};
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use aes_gcm::Aes128Gcm;
+use rand::rngs::ThreadRng;
+use rand::{CryptoRng, Rng, RngCore};
+use std::cell::RefCell;
+use std::convert::TryInto;
+
+// A store that keeps the participant ids of each record encrypted under the server's keyring, so
+// a copy of the records does not reveal who forwarded to whom. The id fields of a record are
+// replaced by one `ids` field holding the key version, a random nonce and the fields encrypted
+// with AES-GCM, bound to the record's address, and are decrypted again when the record is read back during a trace. Sealed ids
+// that fail to decrypt read as tampered through `get_checked`. Records written under a key that
+// is no longer in the keyring read as if their ids were missing.
+
+const ID_FIELDS: [&str; 4] = ["sid", "sid_device", "rid", "rid_device"];
+
+const HEADER_LEN: usize = 16;
+
+pub struct PseudonymousStore<'a, S, R = ThreadRng> {
+    store: &'a S,
+    keyring: &'a Keyring,
+    rng: RefCell<R>,
+}
+
+fn cipher(key: &[u8; 16]) -> Aes128Gcm {
+    Aes128Gcm::new(&prf(key, b"pseudonym").into())
+}
+
+impl<'a, S: Store> PseudonymousStore<'a, S> {
+    pub fn new(store: &'a S, keyring: &'a Keyring) -> PseudonymousStore<'a, S> {
+        PseudonymousStore::new_with_rng(store, keyring, rand::thread_rng())
+    }
+}
+
+impl<'a, S: Store, R: RngCore + CryptoRng> PseudonymousStore<'a, S, R> {
+    // The nonces of the sealed ids are drawn from `rng`.
+    pub fn new_with_rng(store: &'a S, keyring: &'a Keyring, rng: R) -> PseudonymousStore<'a, S, R> {
+        PseudonymousStore {
+            store,
+            keyring,
+            rng: RefCell::new(rng),
+        }
+    }
+
+    fn seal(&self, addr: &[u8], record: &[(&str, &[u8])]) -> Option<Vec<u8>> {
+        let ids: Vec<(&str, &[u8])> = record
+            .iter()
+            .filter(|(f, _)| ID_FIELDS.contains(f))
+            .cloned()
+            .collect();
+        if ids.is_empty() {
+            return None;
+        }
+        let (version, key) = self.keyring.current();
+        let nonce: [u8; 12] = self.rng.borrow_mut().gen();
+        let header = [&version.to_be_bytes()[..], &nonce[..]].concat();
+        let payload = Payload {
+            msg: &encode_fields(&ids),
+            aad: &[addr, &header[..]].concat(),
+        };
+        let ct = cipher(key).encrypt(&nonce.into(), payload).unwrap();
+        Some([header, ct].concat())
+    }
+
+    // Restores the id fields of a record read from the wrapped store.
+    fn open(&self, addr: &[u8], mut record: Record) -> Result<Record, Tampered> {
+        if let Some(sealed) = record.remove("ids") {
+            if let Some(ids) = self.open_ids(addr, &sealed)? {
+                record.extend(ids);
+            }
+        }
+        Ok(record)
+    }
+
+    fn open_ids(&self, addr: &[u8], sealed: &[u8]) -> Result<Option<Record>, Tampered> {
+        if sealed.len() < HEADER_LEN {
+            return Err(Tampered);
+        }
+        let (header, ct) = sealed.split_at(HEADER_LEN);
+        let version = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let nonce: [u8; 12] = header[4..].try_into().unwrap();
+        let key = match self.keyring.get(version) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let payload = Payload {
+            msg: ct,
+            aad: &[addr, header].concat(),
+        };
+        let ids = cipher(key)
+            .decrypt(&nonce.into(), payload)
+            .map_err(|_| Tampered)?;
+        let ids = decode_fields(&ids).ok_or(Tampered)?;
+        Ok(Some(ids.into_iter().collect()))
+    }
+}
+
+// The record with its id fields replaced by their sealed form.
+fn with_sealed<'r>(
+    record: &[(&'r str, &'r [u8])],
+    sealed: &'r Option<Vec<u8>>,
+) -> Vec<(&'r str, &'r [u8])> {
+    let mut fields: Vec<(&str, &[u8])> = record
+        .iter()
+        .filter(|(f, _)| !ID_FIELDS.contains(f))
+        .cloned()
+        .collect();
+    if let Some(sealed) = sealed {
+        fields.push(("ids", sealed));
+    }
+    fields
+}
+
+impl<'a, S: Store, R: RngCore + CryptoRng> Store for PseudonymousStore<'a, S, R> {
+    fn exists(&self, addr: &[u8]) -> bool {
+        self.store.exists(addr)
+    }
+
+    fn get(&self, addr: &[u8]) -> Option<Record> {
+        self.get_checked(addr).ok().flatten()
+    }
+
+    fn get_checked(&self, addr: &[u8]) -> Result<Option<Record>, Tampered> {
+        self.store
+            .get_checked(addr)?
+            .map(|r| self.open(addr, r))
+            .transpose()
+    }
+
+    fn insert(&self, addr: &[u8], record: &[(&str, &[u8])]) -> bool {
+        let sealed = self.seal(addr, record);
+        self.store.insert(addr, &with_sealed(record, &sealed))
+    }
+
+    fn insert_all(&self, records: &[Entry]) -> bool {
+        let sealed: Vec<_> = records.iter().map(|(a, r)| self.seal(a, r)).collect();
+        let fields: Vec<_> = records
+            .iter()
+            .zip(&sealed)
+            .map(|((_, r), s)| with_sealed(r, s))
+            .collect();
+        let entries: Vec<Entry> = records
+            .iter()
+            .zip(&fields)
+            .map(|((addr, _), f)| (*addr, &f[..]))
+            .collect();
+        self.store.insert_all(&entries)
+    }
+
+    fn insert_many(&self, records: &[Entry]) -> Vec<bool> {
+        let sealed: Vec<_> = records.iter().map(|(a, r)| self.seal(a, r)).collect();
+        let fields: Vec<_> = records
+            .iter()
+            .zip(&sealed)
+            .map(|((_, r), s)| with_sealed(r, s))
+            .collect();
+        let entries: Vec<Entry> = records
+            .iter()
+            .zip(&fields)
+            .map(|((addr, _), f)| (*addr, &f[..]))
+            .collect();
+        self.store.insert_many(&entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{MemoryStore, ScanStore};
+    use crate::{path, tree, Participant, StopReason};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use serde::Serialize;
+
+    fn addr(ttr: &impl Serialize) -> Vec<u8> {
+        serde_json::from_value(serde_json::to_value(ttr).unwrap()["addr"].clone()).unwrap()
+    }
+
+    #[test]
+    fn ids_sealed_at_rest() {
+        let inner = MemoryStore::new();
+        let mut keyring = Keyring::generate();
+        let m = b"Plaintext";
+        let (alice, bob) = (Participant::new("alice", b"phone"), Participant::from(7));
+
+        let store = PseudonymousStore::new(&inner, &keyring);
+        let k = rand::random::<[u8; 16]>();
+        let tmd0 = path::new_message(m);
+        let tts = path::generate_tag(&k, m, &tmd0);
+        let ttr = path::svr_process(&store, &tts, &alice, &bob).unwrap();
+        let tmd1 = path::verify_tag(&k, m, &ttr).unwrap();
+
+        let record = inner.get(&addr(&ttr)).unwrap();
+        assert!(ID_FIELDS.iter().all(|f| !record.contains_key(*f)));
+        assert!(!record["ids"].windows(5).any(|w| w == b"alice"));
+
+        // Records written before a rotation still trace
+        keyring.rotate();
+        let store = PseudonymousStore::new(&inner, &keyring);
+        let trace = path::svr_trace(&store, m, &tmd1, &bob);
+        assert_eq!(vec![bob.clone(), alice.clone()], trace.path);
+
+        let trace = path::svr_trace(&inner, m, &tmd1, &bob);
+        assert_eq!(StopReason::MalformedRecord, trace.stop);
+        let other = Keyring::generate();
+        let trace = path::svr_trace(&PseudonymousStore::new(&inner, &other), m, &tmd1, &bob);
+        assert_eq!(StopReason::TamperedRecord, trace.stop);
+    }
+
+    #[test]
+    fn altered_ids_tampered() {
+        let inner = MemoryStore::new();
+        let keyring = Keyring::generate();
+        let store = PseudonymousStore::new(&inner, &keyring);
+        let m = b"Plaintext";
+        let k = rand::random::<[u8; 16]>();
+        let tts = path::generate_tag(&k, m, &path::new_message(m));
+        let ttr = path::svr_process(&store, &tts, &0.into(), &1.into()).unwrap();
+        let tmd = path::verify_tag(&k, m, &ttr).unwrap();
+
+        let mut record = inner.get(&addr(&ttr)).unwrap();
+        *record.get_mut("ids").unwrap().last_mut().unwrap() ^= 1;
+        let fields: Vec<(&str, &[u8])> = record.iter().map(|(f, v)| (&f[..], &v[..])).collect();
+        inner.remove(&addr(&ttr)).unwrap();
+        inner.insert(&addr(&ttr), &fields);
+        assert_eq!(Err(Tampered), store.get_checked(&addr(&ttr)));
+        let trace = path::svr_trace(&store, m, &tmd, &1.into());
+        assert_eq!(StopReason::TamperedRecord, trace.stop);
+    }
+
+    #[test]
+    fn swapped_ids_tampered() {
+        let inner = MemoryStore::new();
+        let keyring = Keyring::generate();
+        let store = PseudonymousStore::new(&inner, &keyring);
+        store.insert(b"addr1", &[("sid", b"alice"), ("rid", b"bob")]);
+        store.insert(b"addr2", &[("sid", b"carol"), ("rid", b"dave")]);
+
+        let mut record1 = inner.get(b"addr1").unwrap();
+        let mut record2 = inner.get(b"addr2").unwrap();
+        std::mem::swap(
+            record1.get_mut("ids").unwrap(),
+            record2.get_mut("ids").unwrap(),
+        );
+        for (addr, record) in [(&b"addr1"[..], record1), (&b"addr2"[..], record2)].iter() {
+            let fields: Vec<(&str, &[u8])> = record.iter().map(|(f, v)| (&f[..], &v[..])).collect();
+            inner.remove(addr).unwrap();
+            inner.insert(addr, &fields);
+            assert_eq!(Err(Tampered), store.get_checked(addr));
+        }
+    }
+
+    #[test]
+    fn seal_with_rng() {
+        let keyring = Keyring::generate();
+        let record: &[(&str, &[u8])] = &[("sid", b"alice"), ("rid", b"bob")];
+        let (inner1, inner2) = (MemoryStore::new(), MemoryStore::new());
+        let store1 = PseudonymousStore::new_with_rng(&inner1, &keyring, StdRng::seed_from_u64(0));
+        let store2 = PseudonymousStore::new_with_rng(&inner2, &keyring, StdRng::seed_from_u64(0));
+        store1.insert(b"addr", record);
+        store2.insert(b"addr", record);
+        assert_eq!(inner1.get(b"addr"), inner2.get(b"addr"));
+        assert_eq!(
+            Some(b"bob".to_vec()),
+            store1.get(b"addr").unwrap().remove("rid")
+        );
+    }
+
+    #[test]
+    fn same_ids_seal_differently() {
+        let inner = MemoryStore::new();
+        let keyring = Keyring::generate();
+        let store = PseudonymousStore::new(&inner, &keyring);
+        let m = b"Plaintext";
+        let (k, tmd) = (rand::random::<[u8; 16]>(), tree::new_message(m));
+        let tts = tree::generate_tag(&k, m, &tmd, 0);
+        let tts2 = tree::generate_tag(&k, m, &tmd, 1);
+        let ttr = tree::svr_process(&store, &tts, &0.into(), &1.into()).unwrap();
+        let ttr2 = tree::svr_process(&store, &tts2, &0.into(), &1.into()).unwrap();
+        assert_ne!(
+            inner.get(&addr(&ttr)).unwrap()["ids"],
+            inner.get(&addr(&ttr2)).unwrap()["ids"]
+        );
+
+        let tmd1 = tree::verify_tag(&k, m, &ttr).unwrap();
+        let trace = tree::svr_trace(&store, m, &tmd1, &1.into());
+        assert_eq!(Participant::from(0), trace.tree.uid);
+    }
+}
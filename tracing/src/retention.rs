@@ -0,0 +1,545 @@
+use crate::audit::{AuditEvent, AuditTrail};
+use crate::keyring::write_file;
+use crate::store::{read_int, ScanStore, Tampered};
+use crate::{path, tree, Clock, StopReason, SystemClock};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+// Records are kept for a retention period after they were processed and then removed by `sweep`.
+// A legal hold exempts the records read by a trace from the sweep until it is released. Holds are
+// named by the case they belong to and kept in a JSON file, and every hold, release and sweep is
+// appended to the audit trail.
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Holds {
+    cases: BTreeMap<String, Hold>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Hold {
+    pub created_at: u64,
+    pub reason: String,
+    pub addrs: BTreeSet<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub enum HoldError {
+    // Only a trace that reached the origin is held
+    Unsuccessful(StopReason),
+    CaseExists(String),
+    UnknownCase(String),
+    Io(io::Error),
+}
+
+// A trace whose records can be held.
+pub trait Visited {
+    fn stop(&self) -> StopReason;
+
+    // The addresses of the records the trace read.
+    fn visited(&self) -> Vec<Vec<u8>>;
+}
+
+impl Visited for path::TraceOutcome {
+    fn stop(&self) -> StopReason {
+        self.stop
+    }
+
+    fn visited(&self) -> Vec<Vec<u8>> {
+        self.addrs.iter().map(|addr| addr.to_vec()).collect()
+    }
+}
+
+impl Visited for path::edit::TraceOutcome {
+    fn stop(&self) -> StopReason {
+        self.stop
+    }
+
+    fn visited(&self) -> Vec<Vec<u8>> {
+        self.addrs.iter().map(|addr| addr.to_vec()).collect()
+    }
+}
+
+// The tree of a trace that reached the origin covers the records walked back from the reporter.
+impl Visited for tree::TraceOutcome {
+    fn stop(&self) -> StopReason {
+        self.stop
+    }
+
+    fn visited(&self) -> Vec<Vec<u8>> {
+        let mut addrs = Vec::new();
+        let mut nodes = vec![&self.tree];
+        while let Some(node) = nodes.pop() {
+            for edge in &node.edges {
+                addrs.push(edge.addr.to_vec());
+                nodes.push(&edge.child);
+            }
+        }
+        addrs
+    }
+}
+
+impl From<io::Error> for HoldError {
+    fn from(e: io::Error) -> HoldError {
+        HoldError::Io(e)
+    }
+}
+
+impl fmt::Display for HoldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HoldError::Unsuccessful(stop) => write!(f, "trace stopped with {:?}", stop),
+            HoldError::CaseExists(case) => write!(f, "case {:?} is already held", case),
+            HoldError::UnknownCase(case) => write!(f, "case {:?} is not held", case),
+            HoldError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Holds {
+    // A missing file holds nothing.
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Holds> {
+        match File::open(path) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Holds::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        write_file(path.as_ref(), &serde_json::to_vec_pretty(self)?)
+    }
+
+    pub fn cases(&self) -> impl Iterator<Item = (&str, &Hold)> {
+        self.cases.iter().map(|(case, hold)| (&case[..], hold))
+    }
+
+    pub fn is_held(&self, addr: &[u8]) -> bool {
+        self.cases.values().any(|hold| hold.addrs.contains(addr))
+    }
+
+    // Holds the records read by a successful trace under `case`, returning how many there are.
+    pub fn place(
+        &mut self,
+        case: &str,
+        reason: &str,
+        trace: &impl Visited,
+        file: impl AsRef<Path>,
+        audit: &AuditTrail,
+    ) -> Result<usize, HoldError> {
+        self.place_with_clock(case, reason, trace, file, audit, &SystemClock)
+    }
+
+    pub fn place_with_clock(
+        &mut self,
+        case: &str,
+        reason: &str,
+        trace: &impl Visited,
+        file: impl AsRef<Path>,
+        audit: &AuditTrail,
+        clock: &impl Clock,
+    ) -> Result<usize, HoldError> {
+        if trace.stop() != StopReason::ReachedOrigin {
+            return Err(HoldError::Unsuccessful(trace.stop()));
+        }
+        if self.cases.contains_key(case) {
+            return Err(HoldError::CaseExists(case.to_string()));
+        }
+        let addrs: BTreeSet<Vec<u8>> = trace.visited().into_iter().collect();
+        let records = addrs.len();
+        self.cases.insert(
+            case.to_string(),
+            Hold {
+                created_at: clock.now(),
+                reason: reason.to_string(),
+                addrs,
+            },
+        );
+        let event = AuditEvent::Hold {
+            case: case.to_string(),
+            reason: reason.to_string(),
+            records,
+        };
+        self.commit(file.as_ref(), audit, clock, event, |holds| {
+            holds.cases.remove(case);
+        })?;
+        Ok(records)
+    }
+
+    // Releases the hold of `case`, returning how many records it held. Records that are also held
+    // by another case stay held.
+    pub fn release(
+        &mut self,
+        case: &str,
+        file: impl AsRef<Path>,
+        audit: &AuditTrail,
+    ) -> Result<usize, HoldError> {
+        self.release_with_clock(case, file, audit, &SystemClock)
+    }
+
+    pub fn release_with_clock(
+        &mut self,
+        case: &str,
+        file: impl AsRef<Path>,
+        audit: &AuditTrail,
+        clock: &impl Clock,
+    ) -> Result<usize, HoldError> {
+        let hold = match self.cases.remove(case) {
+            Some(hold) => hold,
+            None => return Err(HoldError::UnknownCase(case.to_string())),
+        };
+        let records = hold.addrs.len();
+        let event = AuditEvent::Release {
+            case: case.to_string(),
+            records,
+        };
+        self.commit(file.as_ref(), audit, clock, event, |holds| {
+            holds.cases.insert(case.to_string(), hold);
+        })?;
+        Ok(records)
+    }
+
+    // Saves the holds and then appends `event` to the audit trail, so the trail never names a
+    // change the file lacks. If either fails, `undo` reverts the change and the file is saved again.
+    fn commit(
+        &mut self,
+        file: &Path,
+        audit: &AuditTrail,
+        clock: &impl Clock,
+        event: AuditEvent,
+        undo: impl FnOnce(&mut Holds),
+    ) -> Result<(), HoldError> {
+        if let Err(e) = self.save(file).and_then(|()| audit.append(clock, event)) {
+            undo(self);
+            // Restores the file after a failed append; a failed save left it unchanged
+            let _ = self.save(file);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+// The outcome of a sweep. Records that fail to open are listed by `ScanStore::scan_checked`: those
+// whose key has been pruned from the keyring can never be read again and are removed unless held,
+// while tampered records are kept for inspection.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Swept {
+    // Including the keyless records
+    pub removed: usize,
+    // Expired or keyless records that were kept because they are held
+    pub held: usize,
+    #[serde(default)]
+    pub keyless: usize,
+    #[serde(default)]
+    pub tampered: usize,
+}
+
+// Removes the records processed at least `retention` seconds ago that are not held, and those
+// whose key is gone. Records without a readable processing time, such as the links of forward
+// tracing, are kept.
+pub fn sweep(
+    store: &impl ScanStore,
+    holds: &Holds,
+    retention: u64,
+    audit: &AuditTrail,
+) -> io::Result<Swept> {
+    sweep_with_clock(store, holds, retention, audit, &SystemClock)
+}
+
+pub fn sweep_with_clock(
+    store: &impl ScanStore,
+    holds: &Holds,
+    retention: u64,
+    audit: &AuditTrail,
+    clock: &impl Clock,
+) -> io::Result<Swept> {
+    let now = clock.now();
+    let mut swept = Swept::default();
+    let mut expired = Vec::new();
+    store.scan_checked(&mut |addr, record| {
+        let expires = match &record {
+            Ok(Some(record)) => {
+                read_int::<u64>(record, "ts").map_or(false, |ts| ts + retention <= now)
+            }
+            Ok(None) => true,
+            Err(Tampered) => {
+                swept.tampered += 1;
+                false
+            }
+        };
+        if expires && holds.is_held(addr) {
+            swept.held += 1;
+        } else if expires {
+            expired.push((addr.to_vec(), matches!(record, Ok(None))));
+        }
+        Ok(())
+    })?;
+    for (addr, keyless) in &expired {
+        if store.remove(addr)? {
+            swept.removed += 1;
+            swept.keyless += *keyless as usize;
+        }
+    }
+    audit.append(clock, AuditEvent::Sweep(swept.clone()))?;
+    Ok(swept)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditEntry;
+    use crate::encrypted::{self, EncryptedStore};
+    use crate::keyring::Keyring;
+    use crate::store::{MemoryStore, Store};
+    use crate::Participant;
+    use std::fs;
+
+    struct FixedClock(u64);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> u64 {
+            self.0
+        }
+    }
+
+    fn p(uid: u32) -> Participant {
+        Participant::from(uid)
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("{}-{}.json", name, rand::random::<u64>()))
+    }
+
+    fn actions(audit: &AuditTrail) -> Vec<String> {
+        audit
+            .entries()
+            .unwrap()
+            .iter()
+            .map(|e| serde_json::to_value(e).unwrap()["action"].to_string())
+            .collect()
+    }
+
+    #[test]
+    fn held_path_survives_sweep() {
+        let store = MemoryStore::new();
+        let audit_path = temp_path("audit");
+        let audit = AuditTrail::new(&audit_path);
+        let m = b"Plaintext";
+
+        let mut tmd = path::new_message(m);
+        for i in 0..2 {
+            let k = rand::random::<[u8; 16]>();
+            let tts = path::generate_tag(&k, m, &tmd);
+            let ttr =
+                path::svr_process_with_clock(&store, &tts, &p(i), &p(i + 1), &FixedClock(100));
+            tmd = path::verify_tag(&k, m, &ttr.unwrap()).unwrap();
+        }
+        let other = path::generate_tag(&rand::random(), b"Other", &path::new_message(b"Other"));
+        path::svr_process_with_clock(&store, &other, &p(5), &p(6), &FixedClock(100)).unwrap();
+
+        let path = temp_path("holds");
+        let mut holds = Holds::default();
+        let trace = path::svr_trace(&store, m, &tmd, &p(2));
+        let placed =
+            holds.place_with_clock("case-1", "report", &trace, &path, &audit, &FixedClock(150));
+        assert_eq!(2, placed.unwrap());
+        assert!(matches!(
+            holds.place_with_clock("case-1", "report", &trace, &path, &audit, &FixedClock(150)),
+            Err(HoldError::CaseExists(_))
+        ));
+
+        // Only the record outside the hold has expired
+        let mut holds = Holds::load(&path).unwrap();
+        let swept = sweep_with_clock(&store, &holds, 50, &audit, &FixedClock(200)).unwrap();
+        assert_eq!((1, 2), (swept.removed, swept.held));
+        assert_eq!(2, store.len());
+        let trace = path::svr_trace(&store, m, &tmd, &p(2));
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+
+        assert_eq!(
+            2,
+            holds
+                .release_with_clock("case-1", &path, &audit, &FixedClock(250))
+                .unwrap()
+        );
+        assert!(matches!(
+            holds.release("case-1", &path, &audit),
+            Err(HoldError::UnknownCase(_))
+        ));
+        assert_eq!(
+            2,
+            sweep_with_clock(&store, &holds, 50, &audit, &FixedClock(300))
+                .unwrap()
+                .removed
+        );
+        let trace = path::svr_trace(&store, m, &tmd, &p(2));
+        assert!(matches!(
+            holds.place("case-2", "report", &trace, &path, &audit),
+            Err(HoldError::Unsuccessful(StopReason::MissingRecord))
+        ));
+
+        assert_eq!(
+            vec!["\"hold\"", "\"sweep\"", "\"release\"", "\"sweep\""],
+            actions(&audit)
+        );
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&audit_path).unwrap();
+    }
+
+    #[test]
+    fn held_tree_covers_cascade() {
+        let store = MemoryStore::new();
+        let audit_path = temp_path("audit");
+        let audit = AuditTrail::new(&audit_path);
+        let m = b"Plaintext";
+
+        let k0 = rand::random::<[u8; 16]>();
+        let tmd0 = tree::new_message(m);
+        let ttr = tree::svr_process(&store, &tree::generate_tag(&k0, m, &tmd0, 0), &p(0), &p(1));
+        let tmd1 = tree::verify_tag(&k0, m, &ttr.unwrap()).unwrap();
+        let k1 = rand::random::<[u8; 16]>();
+        let mut tmd2 = None;
+        for (ctr, rid) in [2, 3].iter().enumerate() {
+            let tts = tree::generate_tag(&k1, m, &tmd1, ctr as u32);
+            let ttr = tree::svr_process(&store, &tts, &p(1), &p(*rid)).unwrap();
+            tmd2.get_or_insert(tree::verify_tag(&k1, m, &ttr).unwrap());
+        }
+
+        let trace = tree::svr_trace(&store, m, &tmd2.unwrap(), &p(2));
+        let path = temp_path("holds");
+        let mut holds = Holds::default();
+        assert_eq!(
+            3,
+            holds
+                .place("case-1", "report", &trace, &path, &audit)
+                .unwrap()
+        );
+        let mut held = 0;
+        store
+            .scan(&mut |addr, _| {
+                held += holds.is_held(addr) as usize;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(3, held);
+
+        assert_eq!(0, sweep(&store, &holds, 0, &audit).unwrap().removed);
+        assert_eq!(3, store.len());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&audit_path).unwrap();
+    }
+
+    #[test]
+    fn unaudited_hold_undone() {
+        let store = MemoryStore::new();
+        let m = b"Plaintext";
+        let k = rand::random::<[u8; 16]>();
+        let tts = path::generate_tag(&k, m, &path::new_message(m));
+        let ttr = path::svr_process(&store, &tts, &p(0), &p(1)).unwrap();
+        let trace = path::svr_trace(&store, m, &path::verify_tag(&k, m, &ttr).unwrap(), &p(1));
+
+        // The audit trail cannot be written in a missing directory
+        let path = temp_path("holds");
+        let audit = AuditTrail::new(temp_path("missing").join("audit.log"));
+        let mut holds = Holds::default();
+        assert!(matches!(
+            holds.place("case-1", "report", &trace, &path, &audit),
+            Err(HoldError::Io(_))
+        ));
+        assert_eq!(0, holds.cases().count());
+        assert_eq!(0, Holds::load(&path).unwrap().cases().count());
+
+        let audit_path = temp_path("audit");
+        let audit = AuditTrail::new(&audit_path);
+        assert!(matches!(
+            holds.place(
+                "case-1",
+                "report",
+                &trace,
+                temp_path("missing").join("holds.json"),
+                &audit
+            ),
+            Err(HoldError::Io(_))
+        ));
+        assert_eq!(0, holds.cases().count());
+        assert!(audit.entries().unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sweep_sees_unreadable_records() {
+        let inner = MemoryStore::new();
+        let mut keyring = Keyring::generate();
+        let audit_path = temp_path("audit");
+        let audit = AuditTrail::new(&audit_path);
+        let m = b"Plaintext";
+
+        // Two records under the first key, one of them held, and one under the second
+        let mut traces = Vec::new();
+        for (uid, msg) in [(0, &b"held"[..]), (2, &b"other"[..])].iter() {
+            let store = EncryptedStore::new(&inner, &keyring);
+            let k = rand::random::<[u8; 16]>();
+            let tts = path::generate_tag(&k, msg, &path::new_message(msg));
+            let ttr =
+                path::svr_process_with_clock(&store, &tts, &p(*uid), &p(uid + 1), &FixedClock(100));
+            let tmd = path::verify_tag(&k, msg, &ttr.unwrap()).unwrap();
+            traces.push(path::svr_trace(&store, msg, &tmd, &p(uid + 1)));
+        }
+        let holds_path = temp_path("holds");
+        let mut holds = Holds::default();
+        holds
+            .place("case-1", "report", &traces[0], &holds_path, &audit)
+            .unwrap();
+        keyring.rotate_with_rng(&FixedClock(100), &mut rand::thread_rng());
+        let store = EncryptedStore::new(&inner, &keyring);
+        let tts = path::generate_tag(&rand::random(), m, &path::new_message(m));
+        path::svr_process_with_clock(&store, &tts, &p(4), &p(5), &FixedClock(100)).unwrap();
+        inner.insert(b"forged", &[("record", b"not sealed")]);
+
+        // The first key is kept for the held record
+        let in_use = encrypted::key_versions(&inner).unwrap();
+        assert!(keyring
+            .prune_unused_with_clock(50, &in_use, &FixedClock(200))
+            .is_empty());
+        assert_eq!(vec![1], keyring.prune_with_clock(50, &FixedClock(200)));
+
+        let store = EncryptedStore::new(&inner, &keyring);
+        let swept = sweep_with_clock(&store, &holds, 1000, &audit, &FixedClock(200)).unwrap();
+        let expected = Swept {
+            removed: 1,
+            held: 1,
+            keyless: 1,
+            tampered: 1,
+        };
+        assert_eq!(expected, swept);
+        assert_eq!(3, inner.len());
+        assert_eq!(
+            AuditEvent::Sweep(expected),
+            audit.entries().unwrap().pop().unwrap().event
+        );
+
+        // A record under a key rotated in after this keyring was loaded is not keyless
+        let mut newer = keyring.clone();
+        newer.rotate_with_rng(&FixedClock(200), &mut rand::thread_rng());
+        EncryptedStore::new(&inner, &newer).insert(b"newer", &[("ts", b"100")]);
+        let swept = sweep_with_clock(&store, &holds, 1000, &audit, &FixedClock(200)).unwrap();
+        assert_eq!(0, swept.removed);
+        assert!(inner.exists(b"newer"));
+
+        let old: AuditEntry =
+            serde_json::from_str(r#"{"at":1,"action":"sweep","removed":2,"held":0}"#).unwrap();
+        assert!(matches!(
+            old.event,
+            AuditEvent::Sweep(Swept {
+                removed: 2,
+                keyless: 0,
+                ..
+            })
+        ));
+        fs::remove_file(&holds_path).unwrap();
+        fs::remove_file(&audit_path).unwrap();
+    }
+}
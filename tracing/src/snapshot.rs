@@ -0,0 +1,507 @@
+use crate::store::{check_namespace, encode_fields, Entry, Namespaced, ScanStore, Store};
+use crate::{Clock, SystemClock};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Sha3_256};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, Seek, SeekFrom, Write};
+
+// Snapshots hold every record of a namespace, the records one deployment keeps through
+// `Namespaced`, as lines of JSON: a header naming the format version, namespace, scheme and
+// cryptographic suite, one line per record with its address within the namespace and its fields in
+// hex, and an end line with the record count and a SHA3-256 digest over the header and records.
+// Records are copied as stored, so records written through `EncryptedStore` or `PseudonymousStore`
+// stay encrypted and read back only with the same keyring.
+
+pub const FORMAT: &str = "tracing-snapshot";
+pub const VERSION: u32 = 1;
+
+// Primitives of the tracing schemes, and of the schemes with records encrypted at rest.
+pub const SUITE: &str = "aes128-hmac-sha3-256";
+pub const SUITE_ENCRYPTED: &str = "aes128-hmac-sha3-256+aes128-gcm";
+
+// Mixed snapshots hold records of both schemes.
+pub const SCHEMES: [&str; 3] = ["path", "tree", "mixed"];
+
+const IMPORT_BATCH: usize = 1000;
+
+type Fields = Vec<(String, Vec<u8>)>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Header {
+    pub format: String,
+    pub version: u32,
+    pub namespace: String,
+    pub scheme: String,
+    pub suite: String,
+    pub created_at: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    pub header: Header,
+    pub records: u64,
+    pub digest: [u8; 32],
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    Malformed(String),
+    UnsupportedVersion(u32),
+    CountMismatch { expected: u64, found: u64 },
+    DigestMismatch,
+    // The snapshot's records use another suite than the target store reads
+    SuiteMismatch { expected: String, found: String },
+    // The target store already holds a record at this address
+    AddressTaken(Vec<u8>),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Line {
+    Header(Header),
+    Record {
+        addr: String,
+        fields: BTreeMap<String, String>,
+    },
+    End {
+        records: u64,
+        digest: String,
+    },
+}
+
+impl Header {
+    pub fn new(namespace: &str, scheme: &str, suite: &str) -> Header {
+        Header {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            namespace: namespace.to_string(),
+            scheme: scheme.to_string(),
+            suite: suite.to_string(),
+            created_at: SystemClock.now(),
+        }
+    }
+}
+
+impl Summary {
+    pub fn digest_hex(&self) -> String {
+        to_hex(&self.digest)
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> SnapshotError {
+        SnapshotError::Io(e)
+    }
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::Malformed(e) => write!(f, "malformed snapshot: {}", e),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::CountMismatch { expected, found } => write!(
+                f,
+                "snapshot holds {} records but declares {}",
+                found, expected
+            ),
+            SnapshotError::DigestMismatch => write!(f, "snapshot digest does not match"),
+            SnapshotError::SuiteMismatch { expected, found } => write!(
+                f,
+                "snapshot records use suite {} but {} is expected",
+                found, expected
+            ),
+            SnapshotError::AddressTaken(addr) => {
+                write!(f, "address {} is already stored", to_hex(addr))
+            }
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{:02x}", b);
+        s
+    })
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, SnapshotError> {
+    let malformed = || SnapshotError::Malformed(format!("invalid hex {:?}", s));
+    if s.len() % 2 != 0 {
+        return Err(malformed());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(malformed)
+        })
+        .collect()
+}
+
+fn write_line(w: &mut impl Write, line: &Line) -> io::Result<()> {
+    serde_json::to_writer(&mut *w, line)?;
+    w.write_all(b"\n")
+}
+
+// The digest covers the header and each record's address and fields, with fields in name order.
+fn digest_record(h: &mut Sha3_256, addr: &[u8], fields: &[(&str, &[u8])]) {
+    h.input((addr.len() as u32).to_be_bytes());
+    h.input(addr);
+    let encoded = encode_fields(fields);
+    h.input((encoded.len() as u32).to_be_bytes());
+    h.input(&encoded);
+}
+
+fn finish(h: Sha3_256) -> [u8; 32] {
+    let mut digest = [0; 32];
+    digest.copy_from_slice(h.result().as_slice());
+    digest
+}
+
+// Writes the records of the header's namespace in `store`.
+pub fn export(
+    store: &impl ScanStore,
+    header: &Header,
+    w: &mut impl Write,
+) -> Result<Summary, SnapshotError> {
+    let store = Namespaced::new(store, &header.namespace)?;
+    let mut h = Sha3_256::new();
+    h.input(serde_json::to_vec(header).unwrap());
+    write_line(w, &Line::Header(header.clone()))?;
+
+    let mut records = 0;
+    store.scan(&mut |addr, record| {
+        let fields: BTreeMap<&str, &[u8]> = record.iter().map(|(f, v)| (&f[..], &v[..])).collect();
+        let fields: Vec<(&str, &[u8])> = fields.into_iter().collect();
+        digest_record(&mut h, addr, &fields);
+        records += 1;
+        write_line(
+            w,
+            &Line::Record {
+                addr: to_hex(addr),
+                fields: fields
+                    .iter()
+                    .map(|(f, v)| (f.to_string(), to_hex(v)))
+                    .collect(),
+            },
+        )
+    })?;
+
+    let digest = finish(h);
+    write_line(
+        w,
+        &Line::End {
+            records,
+            digest: to_hex(&digest),
+        },
+    )?;
+    w.flush()?;
+    Ok(Summary {
+        header: header.clone(),
+        records,
+        digest,
+    })
+}
+
+// Reads a snapshot through to its end line, passing each record to `f`.
+fn read(
+    r: impl BufRead,
+    mut f: impl FnMut(Vec<u8>, Fields) -> Result<(), SnapshotError>,
+) -> Result<Summary, SnapshotError> {
+    let mut lines = r.lines();
+    let mut next = || -> Result<Line, SnapshotError> {
+        let line = lines
+            .next()
+            .ok_or_else(|| SnapshotError::Malformed("unexpected end of file".to_string()))??;
+        serde_json::from_str(&line).map_err(|e| SnapshotError::Malformed(e.to_string()))
+    };
+
+    let header = match next()? {
+        Line::Header(header) => header,
+        _ => return Err(SnapshotError::Malformed("missing header".to_string())),
+    };
+    if header.format != FORMAT {
+        return Err(SnapshotError::Malformed(format!(
+            "unknown format {:?}",
+            header.format
+        )));
+    }
+    if header.version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(header.version));
+    }
+    check_namespace(&header.namespace).map_err(|e| SnapshotError::Malformed(e.to_string()))?;
+    if !SCHEMES.contains(&&header.scheme[..]) {
+        return Err(SnapshotError::Malformed(format!(
+            "unknown scheme {:?}",
+            header.scheme
+        )));
+    }
+    if ![SUITE, SUITE_ENCRYPTED].contains(&&header.suite[..]) {
+        return Err(SnapshotError::Malformed(format!(
+            "unknown suite {:?}",
+            header.suite
+        )));
+    }
+    let mut h = Sha3_256::new();
+    h.input(serde_json::to_vec(&header).unwrap());
+
+    let mut found = 0;
+    loop {
+        match next()? {
+            Line::Record { addr, fields } => {
+                let addr = from_hex(&addr)?;
+                let fields = fields
+                    .into_iter()
+                    .map(|(f, v)| Ok((f, from_hex(&v)?)))
+                    .collect::<Result<Vec<_>, SnapshotError>>()?;
+                let borrowed: Vec<(&str, &[u8])> =
+                    fields.iter().map(|(f, v)| (&f[..], &v[..])).collect();
+                digest_record(&mut h, &addr, &borrowed);
+                found += 1;
+                f(addr, fields)?;
+            }
+            Line::End { records, digest } => {
+                if records != found {
+                    return Err(SnapshotError::CountMismatch {
+                        expected: records,
+                        found,
+                    });
+                }
+                let computed = finish(h);
+                if from_hex(&digest)? != computed {
+                    return Err(SnapshotError::DigestMismatch);
+                }
+                return Ok(Summary {
+                    header,
+                    records,
+                    digest: computed,
+                });
+            }
+            Line::Header(_) => return Err(SnapshotError::Malformed("repeated header".to_string())),
+        }
+    }
+}
+
+// Checks a snapshot's count and digest without importing it.
+pub fn verify(r: impl BufRead) -> Result<Summary, SnapshotError> {
+    read(r, |_, _| Ok(()))
+}
+
+// Stores every record of a snapshot in its namespace in `store`, which reads records of `suite`.
+// The whole snapshot is verified, and every address checked to be free, before anything is stored.
+// It is then read again and stored a batch at a time, and if another writer takes an address in
+// the meantime the batches already stored are removed again, so the import is all or nothing.
+pub fn import(
+    store: &impl ScanStore,
+    suite: &str,
+    mut r: impl BufRead + Seek,
+) -> Result<Summary, SnapshotError> {
+    let summary = verify(&mut r)?;
+    if summary.header.suite != suite {
+        return Err(SnapshotError::SuiteMismatch {
+            expected: suite.to_string(),
+            found: summary.header.suite,
+        });
+    }
+    let store = Namespaced::new(store, &summary.header.namespace)?;
+    r.seek(SeekFrom::Start(0))?;
+    read(&mut r, |addr, _| {
+        if store.exists(&addr) {
+            Err(SnapshotError::AddressTaken(addr))
+        } else {
+            Ok(())
+        }
+    })?;
+
+    r.seek(SeekFrom::Start(0))?;
+    let mut stored = Vec::new();
+    let mut batch = Vec::new();
+    let result = read(r, |addr, fields| {
+        batch.push((addr, fields));
+        if batch.len() == IMPORT_BATCH {
+            insert_batch(&store, &mut batch, &mut stored)?;
+        }
+        Ok(())
+    })
+    .and_then(|summary| {
+        insert_batch(&store, &mut batch, &mut stored)?;
+        Ok(summary)
+    });
+    if result.is_err() {
+        for addr in &stored {
+            store.remove(addr)?;
+        }
+    }
+    result
+}
+
+// Stores the batch whole or not at all, moving its addresses to `stored` once it is.
+fn insert_batch(
+    store: &impl Store,
+    batch: &mut Vec<(Vec<u8>, Fields)>,
+    stored: &mut Vec<Vec<u8>>,
+) -> Result<(), SnapshotError> {
+    let fields: Vec<Vec<(&str, &[u8])>> = batch
+        .iter()
+        .map(|(_, fields)| fields.iter().map(|(f, v)| (&f[..], &v[..])).collect())
+        .collect();
+    let entries: Vec<Entry> = batch
+        .iter()
+        .zip(&fields)
+        .map(|((addr, _), fields)| (&addr[..], &fields[..]))
+        .collect();
+    if !store.insert_all(&entries) {
+        let (addr, _) = batch
+            .iter()
+            .find(|(addr, _)| store.exists(addr))
+            .unwrap_or(&batch[0]);
+        return Err(SnapshotError::AddressTaken(addr.clone()));
+    }
+    stored.extend(batch.drain(..).map(|(addr, _)| addr));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::{path, tree, Participant, StopReason};
+    use std::io::Cursor;
+
+    fn p(uid: u32) -> Participant {
+        Participant::from(uid)
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+        let source = Namespaced::new(&conn, "test").unwrap();
+        let m = b"Plaintext";
+        let mut tmd = tree::new_message(m);
+        for i in 0..3 {
+            let k = rand::random::<[u8; 16]>();
+            let ttr = tree::svr_process(
+                &source,
+                &tree::generate_tag(&k, m, &tmd, 0),
+                &p(i),
+                &p(i + 1),
+            );
+            tmd = tree::verify_tag(&k, m, &ttr.unwrap()).unwrap();
+        }
+        conn.insert(b"other", &[("ct", b"another deployment")]);
+
+        let header = Header::new("test", "tree", SUITE);
+        let mut file = Vec::new();
+        let exported = export(&conn, &header, &mut file).unwrap();
+        assert_eq!(3, exported.records);
+        assert_eq!(exported, verify(&file[..]).unwrap());
+
+        let store = MemoryStore::new();
+        assert_eq!(exported, import(&store, SUITE, Cursor::new(&file)).unwrap());
+        assert_eq!(3, store.len());
+        let trace = tree::svr_trace(&Namespaced::new(&store, "test").unwrap(), m, &tmd, &p(3));
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+        assert_eq!(3, trace.hop);
+
+        let mut again = Vec::new();
+        assert_eq!(3, export(&store, &header, &mut again).unwrap().records);
+        match import(&store, SUITE, Cursor::new(&file)) {
+            Err(SnapshotError::AddressTaken(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn altered_snapshot_rejected() {
+        let store = MemoryStore::new();
+        let m = b"Plaintext";
+        let k = rand::random::<[u8; 16]>();
+        let tts = path::generate_tag(&k, m, &path::new_message(m));
+        path::svr_process(
+            &Namespaced::new(&store, "test").unwrap(),
+            &tts,
+            &p(0),
+            &p(1),
+        )
+        .unwrap();
+        let mut file = Vec::new();
+        export(&store, &Header::new("test", "path", SUITE), &mut file).unwrap();
+        let text = String::from_utf8(file).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+
+        let dropped = [lines[0], lines[2]].join("\n");
+        match verify(dropped.as_bytes()) {
+            Err(SnapshotError::CountMismatch {
+                expected: 1,
+                found: 0,
+            }) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Nothing is stored from a snapshot that fails to verify
+        let renamed = text.replacen("\"namespace\":\"test\"", "\"namespace\":\"prod\"", 1);
+        let target = MemoryStore::new();
+        match import(&target, SUITE, Cursor::new(&renamed)) {
+            Err(SnapshotError::DigestMismatch) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(target.is_empty());
+
+        match import(&target, SUITE_ENCRYPTED, Cursor::new(&text)) {
+            Err(SnapshotError::SuiteMismatch { .. }) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        let nested = text.replacen("\"namespace\":\"test\"", "\"namespace\":\"test:eu\"", 1);
+        match verify(nested.as_bytes()) {
+            Err(SnapshotError::Malformed(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        let relabeled = text.replacen("\"scheme\":\"path\"", "\"scheme\":\"ring\"", 1);
+        match verify(relabeled.as_bytes()) {
+            Err(SnapshotError::Malformed(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let newer = text.replacen("\"version\":1", "\"version\":2", 1);
+        match verify(newer.as_bytes()) {
+            Err(SnapshotError::UnsupportedVersion(2)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        match verify(&text.as_bytes()[..text.len() - 10]) {
+            Err(SnapshotError::Malformed(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn conflicting_import_unchanged() {
+        let source = MemoryStore::new();
+        let ns = Namespaced::new(&source, "test").unwrap();
+        for i in 0..2 * IMPORT_BATCH as u32 + 1 {
+            assert!(ns.insert(&i.to_be_bytes(), &[("ct", b"record")]));
+        }
+        let mut file = Vec::new();
+        export(&source, &Header::new("test", "path", SUITE), &mut file).unwrap();
+
+        // The last address, in the last batch, is already taken in the target
+        let target = MemoryStore::new();
+        let taken = (2 * IMPORT_BATCH as u32).to_be_bytes();
+        let record: &[(&str, &[u8])] = &[("ct", b"existing")];
+        assert!(Namespaced::new(&target, "test")
+            .unwrap()
+            .insert(&taken, record));
+        match import(&target, SUITE, Cursor::new(&file)) {
+            Err(SnapshotError::AddressTaken(addr)) => assert_eq!(taken.to_vec(), addr),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(1, target.len());
+    }
+}
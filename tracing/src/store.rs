@@ -0,0 +1,652 @@
+use crate::{Participant, UserId};
+use redis::{Commands, PipelineCommands};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::io;
+
+pub type Record = HashMap<String, Vec<u8>>;
+
+// A record as `get_checked` reads it.
+pub type Checked = Result<Option<Record>, Tampered>;
+
+// An address and the fields to store there.
+pub type Entry<'a> = (&'a [u8], &'a [(&'a str, &'a [u8])]);
+
+// Storage backend for the records written by `svr_process`. Integers are stored as decimal
+// strings so records stay readable from a Redis client.
+pub trait Store {
+    fn exists(&self, addr: &[u8]) -> bool;
+
+    fn get(&self, addr: &[u8]) -> Option<Record>;
+
+    // Like `get`, but tells a record that failed an integrity check apart from a missing one.
+    fn get_checked(&self, addr: &[u8]) -> Result<Option<Record>, Tampered> {
+        Ok(self.get(addr))
+    }
+
+    // Stores the record unless the address is already filled, returning whether it was stored.
+    fn insert(&self, addr: &[u8], record: &[(&str, &[u8])]) -> bool;
+
+    // Stores every record only if none of the addresses is filled or repeated, atomically.
+    fn insert_all(&self, records: &[Entry]) -> bool;
+
+    // Inserts each record independently, returning whether each was stored.
+    fn insert_many(&self, records: &[Entry]) -> Vec<bool> {
+        records
+            .iter()
+            .map(|(addr, record)| self.insert(addr, record))
+            .collect()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tampered;
+
+// A storage backend whose records can be listed and removed, for snapshots and the retention
+// sweep.
+pub trait ScanStore: Store {
+    // Calls `f` with every stored address and record until `f` fails.
+    fn scan(&self, f: &mut dyn FnMut(&[u8], Record) -> io::Result<()>) -> io::Result<()> {
+        self.scan_prefix(&[], f)
+    }
+
+    // Like `scan`, but lists every stored address with its record as `get_checked` reads it, so
+    // records that fail to open are listed too.
+    fn scan_checked(&self, f: &mut dyn FnMut(&[u8], Checked) -> io::Result<()>) -> io::Result<()> {
+        self.scan(&mut |addr, record| f(addr, Ok(Some(record))))
+    }
+
+    // Like `scan`, but only for the addresses starting with `prefix`.
+    fn scan_prefix(
+        &self,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], Record) -> io::Result<()>,
+    ) -> io::Result<()>;
+
+    // Removes the record at `addr`, returning whether there was one.
+    fn remove(&self, addr: &[u8]) -> io::Result<bool>;
+
+    // Replaces the record at `addr` with `record` in one step, only if it still reads as `old`.
+    // Returns whether it was replaced.
+    fn replace(&self, addr: &[u8], old: &Record, record: &[(&str, &[u8])]) -> io::Result<bool>;
+}
+
+fn has_duplicates(records: &[Entry]) -> bool {
+    let mut addrs: Vec<&[u8]> = records.iter().map(|(addr, _)| *addr).collect();
+    addrs.sort();
+    addrs.windows(2).any(|w| w[0] == w[1])
+}
+
+impl Store for redis::Connection {
+    fn exists(&self, addr: &[u8]) -> bool {
+        Commands::exists(self, addr).unwrap()
+    }
+
+    fn get(&self, addr: &[u8]) -> Option<Record> {
+        let record: Record = self.hgetall(addr).unwrap();
+        if record.is_empty() {
+            None
+        } else {
+            Some(record)
+        }
+    }
+
+    fn insert(&self, addr: &[u8], record: &[(&str, &[u8])]) -> bool {
+        self.insert_many(&[(addr, record)])[0]
+    }
+
+    fn insert_all(&self, records: &[Entry]) -> bool {
+        if records.is_empty() {
+            return true;
+        }
+        if has_duplicates(records) {
+            return false;
+        }
+        let addrs: Vec<&[u8]> = records.iter().map(|(addr, _)| *addr).collect();
+        // WATCH aborts the transaction if another client fills an address after the check
+        redis::transaction(self, &addrs, |pipe| {
+            for addr in &addrs {
+                if Commands::exists(self, *addr)? {
+                    return Ok(Some(false));
+                }
+            }
+            for (addr, record) in records {
+                pipe.hset_multiple(*addr, record).ignore();
+            }
+            let stored: Option<()> = pipe.query(self)?;
+            Ok(stored.map(|()| true))
+        })
+        .unwrap()
+    }
+
+    // Each record is written whole by one HSET in a transaction, so it is never left half
+    // written. WATCH retries the transaction if another client fills an address after the check.
+    fn insert_many(&self, records: &[Entry]) -> Vec<bool> {
+        if records.is_empty() {
+            return Vec::new();
+        }
+        let addrs: Vec<&[u8]> = records.iter().map(|(addr, _)| *addr).collect();
+        redis::transaction(self, &addrs, |pipe| {
+            let mut check = redis::pipe();
+            for addr in &addrs {
+                check.exists(*addr);
+            }
+            let exists: Vec<bool> = check.query(self)?;
+            let mut claimed = HashSet::new();
+            let stored: Vec<bool> = addrs
+                .iter()
+                .zip(exists)
+                .map(|(addr, exists)| !exists && claimed.insert(*addr))
+                .collect();
+            for ((addr, record), _) in records.iter().zip(&stored).filter(|(_, s)| **s) {
+                pipe.hset_multiple(*addr, record).ignore();
+            }
+            let done: Option<()> = pipe.query(self)?;
+            Ok(done.map(|()| stored.clone()))
+        })
+        .unwrap()
+    }
+}
+
+fn redis_error(e: redis::RedisError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+// Matches the keys starting with `prefix`, escaping the glob characters in it.
+fn prefix_pattern(prefix: &[u8]) -> Vec<u8> {
+    let mut pattern = Vec::with_capacity(prefix.len() + 1);
+    for &b in prefix {
+        if b"*?[]\\".contains(&b) {
+            pattern.push(b'\\');
+        }
+        pattern.push(b);
+    }
+    pattern.push(b'*');
+    pattern
+}
+
+impl ScanStore for redis::Connection {
+    // Walks the keys a SCAN batch at a time and reads the hashes among them in one pipeline per
+    // batch, so only one batch is held in memory. Keys of other types are skipped. As with SCAN, an
+    // address may be listed twice if keys are added or removed during the walk.
+    fn scan_prefix(
+        &self,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], Record) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let pattern = prefix_pattern(prefix);
+        let mut cursor = 0u64;
+        loop {
+            let (next, addrs): (u64, Vec<Vec<u8>>) = redis::cmd("SCAN")
+                .arg(cursor)
+                .arg("MATCH")
+                .arg(&pattern[..])
+                .arg("COUNT")
+                .arg(1000)
+                .query(self)
+                .map_err(redis_error)?;
+            if !addrs.is_empty() {
+                let mut types = redis::pipe();
+                for addr in &addrs {
+                    types.cmd("TYPE").arg(&addr[..]);
+                }
+                let types: Vec<String> = types.query(self).map_err(redis_error)?;
+                let hashes: Vec<&Vec<u8>> = addrs
+                    .iter()
+                    .zip(types)
+                    .filter(|(_, t)| t == "hash")
+                    .map(|(addr, _)| addr)
+                    .collect();
+                let mut pipe = redis::pipe();
+                for addr in &hashes {
+                    pipe.hgetall(&addr[..]);
+                }
+                let records: Vec<Record> = if hashes.is_empty() {
+                    Vec::new()
+                } else {
+                    pipe.query(self).map_err(redis_error)?
+                };
+                // Records removed since the batch was listed read as empty
+                for (addr, record) in hashes.into_iter().zip(records) {
+                    if !record.is_empty() {
+                        f(addr, record)?;
+                    }
+                }
+            }
+            if next == 0 {
+                return Ok(());
+            }
+            cursor = next;
+        }
+    }
+
+    fn remove(&self, addr: &[u8]) -> io::Result<bool> {
+        let removed: u32 = self.del(addr).map_err(redis_error)?;
+        Ok(removed > 0)
+    }
+
+    // WATCH aborts the transaction if another client changes the record after the comparison
+    fn replace(&self, addr: &[u8], old: &Record, record: &[(&str, &[u8])]) -> io::Result<bool> {
+        redis::transaction(self, &[addr], |pipe| {
+            let current: Record = self.hgetall(addr)?;
+            if current.is_empty() || current != *old {
+                return Ok(Some(false));
+            }
+            pipe.del(addr).ignore().hset_multiple(addr, record).ignore();
+            let done: Option<()> = pipe.query(self)?;
+            Ok(done.map(|()| true))
+        })
+        .map_err(redis_error)
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    records: RefCell<HashMap<Vec<u8>, Record>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        Default::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.borrow().is_empty()
+    }
+}
+
+impl Store for MemoryStore {
+    fn exists(&self, addr: &[u8]) -> bool {
+        self.records.borrow().contains_key(addr)
+    }
+
+    fn get(&self, addr: &[u8]) -> Option<Record> {
+        self.records.borrow().get(addr).cloned()
+    }
+
+    fn insert(&self, addr: &[u8], record: &[(&str, &[u8])]) -> bool {
+        let mut records = self.records.borrow_mut();
+        if records.contains_key(addr) {
+            return false;
+        }
+        let record = record
+            .iter()
+            .map(|(field, value)| (field.to_string(), value.to_vec()))
+            .collect();
+        records.insert(addr.to_vec(), record);
+        true
+    }
+
+    fn insert_all(&self, records: &[Entry]) -> bool {
+        if has_duplicates(records) || records.iter().any(|(addr, _)| self.exists(addr)) {
+            return false;
+        }
+        for (addr, record) in records {
+            self.insert(addr, record);
+        }
+        true
+    }
+}
+
+// Lists the records in address order.
+impl ScanStore for MemoryStore {
+    fn scan_prefix(
+        &self,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], Record) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let records = self.records.borrow();
+        let mut addrs: Vec<&Vec<u8>> = records
+            .keys()
+            .filter(|addr| addr.starts_with(prefix))
+            .collect();
+        addrs.sort();
+        for addr in addrs {
+            f(addr, records[addr].clone())?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, addr: &[u8]) -> io::Result<bool> {
+        Ok(self.records.borrow_mut().remove(addr).is_some())
+    }
+
+    fn replace(&self, addr: &[u8], old: &Record, record: &[(&str, &[u8])]) -> io::Result<bool> {
+        let mut records = self.records.borrow_mut();
+        match records.get_mut(addr) {
+            Some(current) if current == old => {
+                *current = record
+                    .iter()
+                    .map(|(field, value)| (field.to_string(), value.to_vec()))
+                    .collect();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+// A store that keeps the records of one deployment under addresses prefixed with its namespace and
+// a colon, so several deployments can share a backend and each can be listed on its own. The empty
+// namespace adds no prefix and covers the whole backend. Namespaces cannot contain a colon, so no
+// namespace's prefix starts with another's.
+pub struct Namespaced<'a, S> {
+    store: &'a S,
+    prefix: Vec<u8>,
+}
+
+pub fn check_namespace(namespace: &str) -> io::Result<()> {
+    if namespace.contains(':') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("namespace {:?} contains a colon", namespace),
+        ));
+    }
+    Ok(())
+}
+
+impl<'a, S: Store> Namespaced<'a, S> {
+    pub fn new(store: &'a S, namespace: &str) -> io::Result<Namespaced<'a, S>> {
+        check_namespace(namespace)?;
+        let prefix = if namespace.is_empty() {
+            Vec::new()
+        } else {
+            [namespace.as_bytes(), b":"].concat()
+        };
+        Ok(Namespaced { store, prefix })
+    }
+
+    fn addr(&self, addr: &[u8]) -> Vec<u8> {
+        [&self.prefix[..], addr].concat()
+    }
+
+    fn with_addrs<T>(&self, records: &[Entry], f: impl FnOnce(&[Entry]) -> T) -> T {
+        let addrs: Vec<Vec<u8>> = records.iter().map(|(addr, _)| self.addr(addr)).collect();
+        let entries: Vec<Entry> = addrs
+            .iter()
+            .zip(records)
+            .map(|(addr, (_, record))| (&addr[..], *record))
+            .collect();
+        f(&entries)
+    }
+}
+
+impl<'a, S: Store> Store for Namespaced<'a, S> {
+    fn exists(&self, addr: &[u8]) -> bool {
+        self.store.exists(&self.addr(addr))
+    }
+
+    fn get(&self, addr: &[u8]) -> Option<Record> {
+        self.store.get(&self.addr(addr))
+    }
+
+    fn get_checked(&self, addr: &[u8]) -> Result<Option<Record>, Tampered> {
+        self.store.get_checked(&self.addr(addr))
+    }
+
+    fn insert(&self, addr: &[u8], record: &[(&str, &[u8])]) -> bool {
+        self.store.insert(&self.addr(addr), record)
+    }
+
+    fn insert_all(&self, records: &[Entry]) -> bool {
+        self.with_addrs(records, |entries| self.store.insert_all(entries))
+    }
+
+    fn insert_many(&self, records: &[Entry]) -> Vec<bool> {
+        self.with_addrs(records, |entries| self.store.insert_many(entries))
+    }
+}
+
+impl<'a, S: ScanStore> ScanStore for Namespaced<'a, S> {
+    fn scan_prefix(
+        &self,
+        prefix: &[u8],
+        f: &mut dyn FnMut(&[u8], Record) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let n = self.prefix.len();
+        self.store
+            .scan_prefix(&self.addr(prefix), &mut |addr, record| {
+                f(&addr[n..], record)
+            })
+    }
+
+    fn remove(&self, addr: &[u8]) -> io::Result<bool> {
+        self.store.remove(&self.addr(addr))
+    }
+
+    fn replace(&self, addr: &[u8], old: &Record, record: &[(&str, &[u8])]) -> io::Result<bool> {
+        self.store.replace(&self.addr(addr), old, record)
+    }
+}
+
+pub(crate) fn read_array<const N: usize>(record: &Record, field: &str) -> Option<[u8; N]> {
+    let value = record.get(field)?;
+    if value.len() != N {
+        return None;
+    }
+    let mut array = [0; N];
+    array.copy_from_slice(value);
+    Some(array)
+}
+
+// Packs fields into one value, each as its name length, name, value length and value.
+pub(crate) fn encode_fields(fields: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (field, value) in fields {
+        out.push(field.len() as u8);
+        out.extend_from_slice(field.as_bytes());
+        out.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        out.extend_from_slice(value);
+    }
+    out
+}
+
+pub(crate) fn decode_fields(mut data: &[u8]) -> Option<Vec<(String, Vec<u8>)>> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let n = data[0] as usize;
+        let field = data.get(1..1 + n)?;
+        let len = u32::from_be_bytes(data.get(1 + n..5 + n)?.try_into().ok()?) as usize;
+        let value = data.get(5 + n..5 + n + len)?;
+        fields.push((String::from_utf8(field.to_vec()).ok()?, value.to_vec()));
+        data = &data[5 + n + len..];
+    }
+    Some(fields)
+}
+
+// A participant is stored in two fields: the user id under `field` and the device under
+// `field` with a "_device" suffix. Records written before devices were stored have no device
+// field and read as the user's default device.
+pub(crate) fn read_participant(record: &Record, field: &str) -> Option<Participant> {
+    Some(Participant {
+        user: UserId(record.get(field)?.clone()),
+        device: record
+            .get(&format!("{}_device", field))
+            .cloned()
+            .unwrap_or_default(),
+    })
+}
+
+pub(crate) fn read_int<T: std::str::FromStr>(record: &Record, field: &str) -> Option<T> {
+    let value = record.get(field)?;
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store_insert_once() {
+        let store = MemoryStore::new();
+        assert!(!store.exists(b"addr"));
+        assert!(store.insert(b"addr", &[("ct", b"first"), ("sid", b"1")]));
+        assert!(!store.insert(b"addr", &[("ct", b"second")]));
+        assert!(store.exists(b"addr"));
+        assert_eq!(1, store.len());
+
+        let record = store.get(b"addr").unwrap();
+        assert_eq!(b"first".to_vec(), record["ct"]);
+        assert_eq!(Some(1u32), read_int(&record, "sid"));
+        assert_eq!(None::<u32>, read_int(&record, "rid"));
+        assert_eq!(None, read_array::<16>(&record, "ct"));
+        assert_eq!(None, read_array::<16>(&record, "rid"));
+    }
+
+    #[test]
+    fn memory_store_insert_all_or_nothing() {
+        let store = MemoryStore::new();
+        let record: &[(&str, &[u8])] = &[("ct", b"group")];
+        assert!(store.insert_all(&[(b"a", record), (b"b", record)]));
+        assert!(!store.insert_all(&[(b"c", record), (b"b", record)]));
+        assert!(!store.insert_all(&[(b"d", record), (b"d", record)]));
+        assert!(!store.exists(b"c"));
+        assert!(!store.exists(b"d"));
+        assert_eq!(2, store.len());
+    }
+
+    #[test]
+    fn replace_if_unchanged() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+        let memory = MemoryStore::new();
+        let stores: [&dyn ScanStore; 2] = [&memory, &conn];
+        for store in stores.iter() {
+            store.insert(b"addr", &[("ct", b"first"), ("sid", b"1")]);
+            let old = store.get(b"addr").unwrap();
+            assert!(store
+                .replace(b"addr", &old, &[("record", b"sealed")])
+                .unwrap());
+            assert_eq!(
+                vec!["record"],
+                store.get(b"addr").unwrap().keys().collect::<Vec<_>>()
+            );
+            assert!(!store
+                .replace(b"addr", &old, &[("record", b"again")])
+                .unwrap());
+            assert!(!store
+                .replace(b"other", &old, &[("record", b"again")])
+                .unwrap());
+            assert!(!store.exists(b"other"));
+        }
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn read_participant_without_device() {
+        let store = MemoryStore::new();
+        store.insert(
+            b"addr",
+            &[("sid", b"alice"), ("rid", b"bob"), ("rid_device", b"phone")],
+        );
+        let record = store.get(b"addr").unwrap();
+        assert_eq!(
+            Some(Participant::new("alice", b"")),
+            read_participant(&record, "sid")
+        );
+        assert_eq!(
+            Some(Participant::new("bob", b"phone")),
+            read_participant(&record, "rid")
+        );
+        assert_eq!(None, read_participant(&record, "uid"));
+    }
+
+    #[test]
+    fn redis_insert_many_whole_records() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+        let first: &[(&str, &[u8])] = &[("ct", b"first"), ("sid", b"1")];
+        let second: &[(&str, &[u8])] = &[("ct", b"second"), ("sid", b"2")];
+        assert!(conn.insert(b"store-a", first));
+        assert_eq!(
+            vec![false, true, false],
+            conn.insert_many(&[
+                (b"store-a", second),
+                (b"store-b", second),
+                (b"store-b", first)
+            ])
+        );
+        assert_eq!(
+            b"first".to_vec(),
+            Store::get(&conn, b"store-a").unwrap()["ct"]
+        );
+        assert_eq!(b"2".to_vec(), Store::get(&conn, b"store-b").unwrap()["sid"]);
+        assert!(conn.insert_many(&[]).is_empty());
+        assert!(conn.insert_all(&[]));
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn namespaced_store_scoped() {
+        let store = MemoryStore::new();
+        let (prod, test) = (
+            Namespaced::new(&store, "prod").unwrap(),
+            Namespaced::new(&store, "test").unwrap(),
+        );
+        let record: &[(&str, &[u8])] = &[("ct", b"record")];
+        assert!(prod.insert(b"a", record));
+        assert!(test.insert(b"a", record));
+        assert_eq!(
+            vec![true, false],
+            prod.insert_many(&[(b"b", record), (b"a", record)])
+        );
+        assert!(store.exists(b"prod:a"));
+
+        // A namespace cannot nest inside another, or start with another's prefix
+        assert!(Namespaced::new(&store, "prod:eu").is_err());
+        assert!(Namespaced::new(&store, "prod2")
+            .unwrap()
+            .insert(b"c", record));
+
+        let mut listed = Vec::new();
+        prod.scan(&mut |addr, _| {
+            listed.push(addr.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(vec![b"a".to_vec(), b"b".to_vec()], listed);
+        assert!(prod.remove(b"a").unwrap());
+        assert!(!prod.remove(b"a").unwrap());
+        assert!(test.exists(b"a"));
+        assert_eq!(3, store.len());
+    }
+
+    #[test]
+    fn redis_scan_streams_hashes() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+        let record: &[(&str, &[u8])] = &[("ct", b"record")];
+        let ns = Namespaced::new(&conn, "scan*").unwrap();
+        for i in 0..3u8 {
+            assert!(ns.insert(&[i], record));
+        }
+        conn.insert(b"other", record);
+        let _: () = conn.set("scan*:string", "not a record").unwrap();
+
+        let mut listed = Vec::new();
+        ns.scan(&mut |addr, record| {
+            assert_eq!(b"record".to_vec(), record["ct"]);
+            listed.push(addr.to_vec());
+            Ok(())
+        })
+        .unwrap();
+        listed.sort();
+        assert_eq!(vec![vec![0], vec![1], vec![2]], listed);
+        let mut all = 0;
+        ScanStore::scan(&conn, &mut |_, _| {
+            all += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(4, all);
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+}
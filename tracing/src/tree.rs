@@ -1,13 +1,20 @@
 use super::*;
+use crate::store::{read_array, read_int, read_participant, Entry, Store, Tampered};
 use log::info;
-use redis::Commands;
+use rand::rngs::OsRng;
+use rand::{CryptoRng, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read};
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary, Debug))]
 pub struct TraceMetadata {
     bptr: [u8; 16],
     gk: [u8; 16],
 }
 
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary, Debug))]
 pub struct SenderTraceTag {
     addr: [u8; 16],
     ct_ptr: [u8; 16],
@@ -16,35 +23,123 @@ pub struct SenderTraceTag {
     ct_fgk: [u8; 16],
 }
 
+// `com_fgk` is the server's commitment to the `ct_fgk` it stored alongside `ks_fgk`, sent with
+// them rather than with the sender's part of the tag.
+#[derive(Serialize, Deserialize)]
 pub struct RecTraceTag {
     addr: [u8; 16],
     ct_ptr: [u8; 16],
     ct_fgk: [u8; 16],
     ks_fgk: [u8; 16],
+    com_fgk: [u8; 16],
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
 pub struct Tree {
-    uid: u32,
-    children: Vec<Tree>,
+    pub uid: Participant,
+    pub children: Vec<Tree>,
 }
 
-pub fn new_message(_m: &[u8]) -> TraceMetadata {
+// `tampered` is set on nodes whose forwards stopped at a record that failed its integrity check.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceTree {
+    pub uid: Participant,
+    pub edges: Vec<TraceEdge>,
+    pub tampered: bool,
+}
+
+// `ctr` is the sender's generator key counter for the forward, absent when the edge was reached
+// through a pointer not derived from the sender's generator key.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceEdge {
+    pub addr: [u8; 16],
+    pub ctr: Option<u32>,
+    pub wellformed: bool,
+    pub processed_at: Option<u64>,
+    pub child: TraceTree,
+}
+
+impl TraceTree {
+    pub fn to_tree(&self) -> Tree {
+        Tree {
+            uid: self.uid.clone(),
+            children: self.edges.iter().map(|e| e.child.to_tree()).collect(),
+        }
+    }
+}
+
+// `hop` is the number of forwards walked back from the reporter before the trace stopped; the
+// tree is rooted at the furthest sender reached.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TraceOutcome {
+    pub tree: TraceTree,
+    pub stop: StopReason,
+    pub hop: usize,
+    pub origin_confirmed: bool,
+}
+
+pub fn new_message(m: &[u8]) -> TraceMetadata {
+    new_message_with_rng(m, &mut OsRng::new().unwrap())
+}
+
+pub fn new_message_with_rng(_m: &[u8], rng: &mut (impl RngCore + CryptoRng)) -> TraceMetadata {
     TraceMetadata {
         bptr: [0; 16],
-        gk: rand::random::<[u8; 16]>(),
+        gk: rng.gen::<[u8; 16]>(),
     }
 }
 
 pub fn generate_tag(k: &[u8; 16], m: &[u8], md: &TraceMetadata, ctr: u32) -> SenderTraceTag {
+    generate_tag_with_rng(k, m, md, ctr, &mut OsRng::new().unwrap())
+}
+
+pub fn generate_tag_with_rng(
+    k: &[u8; 16],
+    m: &[u8],
+    md: &TraceMetadata,
+    ctr: u32,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> SenderTraceTag {
     let ptr = prf(&md.gk, &ctr.to_be_bytes());
     let addr = prf(&ptr, m);
+    tag_at(k, md, &ptr, addr, rng)
+}
+
+// As `generate_tag`, reading the message from `r` to its end instead of from memory.
+pub fn generate_tag_from_reader(
+    k: &[u8; 16],
+    r: &mut impl Read,
+    md: &TraceMetadata,
+    ctr: u32,
+) -> io::Result<SenderTraceTag> {
+    generate_tag_from_reader_with_rng(k, r, md, ctr, &mut OsRng::new().unwrap())
+}
+
+pub fn generate_tag_from_reader_with_rng(
+    k: &[u8; 16],
+    r: &mut impl Read,
+    md: &TraceMetadata,
+    ctr: u32,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> io::Result<SenderTraceTag> {
+    let ptr = prf(&md.gk, &ctr.to_be_bytes());
+    let addr = prf_reader(&ptr, r)?;
+    Ok(tag_at(k, md, &ptr, addr, rng))
+}
+
+fn tag_at(
+    k: &[u8; 16],
+    md: &TraceMetadata,
+    ptr: &[u8; 16],
+    addr: [u8; 16],
+    rng: &mut (impl RngCore + CryptoRng),
+) -> SenderTraceTag {
     SenderTraceTag {
-        addr: addr,
-        ct_ptr: encipher(k, &ptr),
-        ct_bptr: encipher(&ptr, &md.bptr),
-        ct_gk: encipher(&ptr, &md.gk),
-        ct_fgk: encipher(&ptr, &rand::random::<[u8; 16]>()),
+        addr,
+        ct_ptr: encipher(k, ptr),
+        ct_bptr: encipher(ptr, &md.bptr),
+        ct_gk: encipher(ptr, &md.gk),
+        ct_fgk: encipher(ptr, &rng.gen::<[u8; 16]>()),
     }
 }
 
@@ -61,56 +156,242 @@ pub fn verify_tag(k: &[u8; 16], m: &[u8], ttr: &RecTraceTag) -> Option<TraceMeta
     }
 }
 
+// Additionally checks the forward generator key share the sender delivered against the server's
+// commitment to the one it stored, catching senders whose tag would fail the "Malformed forward
+// generator key" check at trace time. The sender's own generator key never reaches the receiver,
+// so its usage is still only checked at trace time.
+pub fn verify_tag_wellformed(k: &[u8; 16], m: &[u8], ttr: &RecTraceTag) -> Option<TraceMetadata> {
+    if commit_fgk(&ttr.addr, &ttr.ct_fgk, &ttr.ks_fgk) != ttr.com_fgk {
+        info!(target: "verify_tag", "Malformed forward generator key");
+        return None;
+    }
+    verify_tag(k, m, ttr)
+}
+
+fn commit_fgk(addr: &[u8; 16], ct_fgk: &[u8; 16], ks_fgk: &[u8; 16]) -> [u8; 16] {
+    hash(&[&b"fgk"[..], &addr[..], &ct_fgk[..], &ks_fgk[..]].concat())
+}
+
 pub fn svr_process(
-    conn: &redis::Connection,
+    conn: &impl Store,
     tts: &SenderTraceTag,
-    sid: u32,
-    rid: u32,
+    sid: &Participant,
+    rid: &Participant,
 ) -> Option<RecTraceTag> {
-    let addr_filled: bool = conn.exists(&tts.addr).unwrap();
+    svr_process_with_rng(conn, tts, sid, rid, &mut OsRng::new().unwrap())
+}
 
-    if addr_filled {
-        None
-    } else {
-        let ks_fgk = rand::random::<[u8; 16]>();
-        let _: () = conn
-            .hset_multiple(
-                &tts.addr,
-                &[
-                    ("ct_bptr", &tts.ct_bptr),
-                    ("ct_gk", &tts.ct_gk),
-                    ("ct_fgk", &tts.ct_fgk),
-                    ("ks_fgk", &ks_fgk),
-                ],
-            )
-            .unwrap();
-        let _: () = conn
-            .hset_multiple(&tts.addr, &[("sid", sid), ("rid", rid)])
-            .unwrap();
-        Some(RecTraceTag {
-            addr: tts.addr.clone(),
-            ct_ptr: tts.ct_ptr.clone(),
-            ct_fgk: tts.ct_fgk.clone(),
-            ks_fgk: ks_fgk,
-        })
+pub fn svr_process_with_rng(
+    conn: &impl Store,
+    tts: &SenderTraceTag,
+    sid: &Participant,
+    rid: &Participant,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Option<RecTraceTag> {
+    svr_process_with_clock(conn, tts, sid, rid, &SystemClock, rng)
+}
+
+pub fn svr_process_with_clock(
+    conn: &impl Store,
+    tts: &SenderTraceTag,
+    sid: &Participant,
+    rid: &Participant,
+    clock: &impl Clock,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Option<RecTraceTag> {
+    svr_store_records(&[(tts, sid, rid)], clock, rng, |entries| {
+        vec![conn.insert(entries[0].0, entries[0].1)]
+    })
+    .pop()
+    .unwrap()
+}
+
+// Tags for a send to a group, one per recipient key, using consecutive counters from `ctr`.
+pub fn generate_group_tags(
+    ks: &[[u8; 16]],
+    m: &[u8],
+    md: &TraceMetadata,
+    ctr: u32,
+) -> Vec<SenderTraceTag> {
+    ks.iter()
+        .zip(ctr..)
+        .map(|(k, ctr)| generate_tag(k, m, md, ctr))
+        .collect()
+}
+
+// Processes a send to a group, one tag per recipient in `rids`. Either every recipient's record is
+// stored or, if any address is already filled, none is. A group needs at least one recipient.
+pub fn svr_process_group(
+    conn: &impl Store,
+    tts: &[SenderTraceTag],
+    sid: &Participant,
+    rids: &[Participant],
+) -> Option<Vec<RecTraceTag>> {
+    svr_process_group_with_rng(conn, tts, sid, rids, &mut OsRng::new().unwrap())
+}
+
+pub fn svr_process_group_with_rng(
+    conn: &impl Store,
+    tts: &[SenderTraceTag],
+    sid: &Participant,
+    rids: &[Participant],
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Option<Vec<RecTraceTag>> {
+    svr_process_group_with_clock(conn, tts, sid, rids, &SystemClock, rng)
+}
+
+pub fn svr_process_group_with_clock(
+    conn: &impl Store,
+    tts: &[SenderTraceTag],
+    sid: &Participant,
+    rids: &[Participant],
+    clock: &impl Clock,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Option<Vec<RecTraceTag>> {
+    if tts.len() != rids.len() || tts.is_empty() {
+        return None;
     }
+    let items: Vec<_> = tts
+        .iter()
+        .zip(rids)
+        .map(|(tts, rid)| (tts, sid, rid))
+        .collect();
+    svr_store_records(&items, clock, rng, |entries| {
+        vec![conn.insert_all(entries); entries.len()]
+    })
+    .into_iter()
+    .collect()
+}
+
+// Processes independent (tag, sid, rid) items in one round trip to the store, with a result per
+// item as from `svr_process`.
+pub fn svr_process_batch(
+    conn: &impl Store,
+    items: &[(SenderTraceTag, Participant, Participant)],
+) -> Vec<Option<RecTraceTag>> {
+    svr_process_batch_with_rng(conn, items, &mut OsRng::new().unwrap())
+}
+
+pub fn svr_process_batch_with_rng(
+    conn: &impl Store,
+    items: &[(SenderTraceTag, Participant, Participant)],
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Vec<Option<RecTraceTag>> {
+    svr_process_batch_with_clock(conn, items, &SystemClock, rng)
+}
+
+pub fn svr_process_batch_with_clock(
+    conn: &impl Store,
+    items: &[(SenderTraceTag, Participant, Participant)],
+    clock: &impl Clock,
+    rng: &mut (impl RngCore + CryptoRng),
+) -> Vec<Option<RecTraceTag>> {
+    let items: Vec<_> = items
+        .iter()
+        .map(|(tts, sid, rid)| (tts, sid, rid))
+        .collect();
+    svr_store_records(&items, clock, rng, |entries| conn.insert_many(entries))
+}
+
+// Builds the record for each item and hands them to `store`, which reports which were stored.
+fn svr_store_records(
+    items: &[(&SenderTraceTag, &Participant, &Participant)],
+    clock: &impl Clock,
+    rng: &mut (impl RngCore + CryptoRng),
+    store: impl FnOnce(&[Entry]) -> Vec<bool>,
+) -> Vec<Option<RecTraceTag>> {
+    let ks_fgk: Vec<[u8; 16]> = items.iter().map(|_| rng.gen()).collect();
+    let ts = clock.now().to_string();
+    let fields: Vec<[(&str, &[u8]); 9]> = items
+        .iter()
+        .zip(&ks_fgk)
+        .map(|((tts, sid, rid), ks_fgk)| {
+            [
+                ("ct_bptr", &tts.ct_bptr[..]),
+                ("ct_gk", &tts.ct_gk[..]),
+                ("ct_fgk", &tts.ct_fgk[..]),
+                ("ks_fgk", &ks_fgk[..]),
+                ("sid", sid.user.as_bytes()),
+                ("sid_device", &sid.device[..]),
+                ("rid", rid.user.as_bytes()),
+                ("rid_device", &rid.device[..]),
+                ("ts", ts.as_bytes()),
+            ]
+        })
+        .collect();
+    let entries: Vec<Entry> = items
+        .iter()
+        .zip(&fields)
+        .map(|((tts, _, _), fields)| (&tts.addr[..], &fields[..]))
+        .collect();
+
+    store(&entries)
+        .into_iter()
+        .zip(items.iter().zip(ks_fgk))
+        .map(|(stored, ((tts, _, _), ks_fgk))| {
+            if stored {
+                Some(RecTraceTag {
+                    addr: tts.addr,
+                    ct_ptr: tts.ct_ptr,
+                    ct_fgk: tts.ct_fgk,
+                    ks_fgk,
+                    com_fgk: commit_fgk(&tts.addr, &tts.ct_fgk, &ks_fgk),
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
 }
 
-pub fn svr_trace(conn: &redis::Connection, m: &[u8], md: &TraceMetadata, uid: u32) -> Tree {
-    let mut root = uid;
+pub fn svr_trace(
+    conn: &impl Store,
+    m: &[u8],
+    md: &TraceMetadata,
+    uid: &Participant,
+) -> TraceOutcome {
+    let mut root = uid.clone();
     let mut root_gk = md.gk;
     let mut gk = md.gk;
     let mut bptr = md.bptr;
-    let mut addr = prf(&bptr, m);
-    let mut prev_sid = uid;
+    let mut addr: [u8; 16];
+    let mut prev_sid = uid.clone();
+    let mut hop = 0;
 
-    while conn.exists(&addr).unwrap() {
-        let (ct_bptr, ct_gk, ct_fgk, ks_platform, sid, rid) = svr_read_state(conn, &addr);
+    let stop = loop {
+        if bptr == [0; 16] {
+            info!(target: "root_traceback", "Reached origin");
+            break StopReason::ReachedOrigin;
+        }
+        addr = prf(&bptr, m);
+        let State {
+            ct_bptr,
+            ct_gk,
+            ct_fgk,
+            ks_fgk: ks_platform,
+            sid,
+            rid,
+            ts,
+        } = match svr_read_state(conn, &addr) {
+            Ok(state) => state,
+            Err(StopReason::MissingRecord) => {
+                info!(target: "root_traceback", "Missing record");
+                break StopReason::MissingRecord;
+            }
+            Err(StopReason::TamperedRecord) => {
+                info!(target: "root_traceback", "Tampered record");
+                break StopReason::TamperedRecord;
+            }
+            Err(stop) => {
+                info!(target: "root_traceback", "Malformed record");
+                break stop;
+            }
+        };
 
         // Identity matching
-        if prev_sid != rid {
+        if !prev_sid.same_user(&rid) {
             info!(target: "root_traceback", "Identity mismatch: {}, {}", prev_sid, rid);
-            break;
+            break StopReason::IdentityMismatch;
         }
 
         // Wellformedness check of forward generator key
@@ -118,14 +399,15 @@ pub fn svr_trace(conn: &redis::Connection, m: &[u8], md: &TraceMetadata, uid: u3
         let fgk = hash(&[&ks_sender[..], &ks_platform[..]].concat());
         if gk != fgk {
             info!(target: "root_traceback", "Malformed forward generator key");
-            break;
+            break StopReason::MalformedForwardGeneratorKey;
         }
 
         // Move current root to sender
         gk = decipher(&bptr, &ct_gk);
-        root = sid;
+        root = sid.clone();
         root_gk = gk;
-        prev_sid = sid;
+        prev_sid = sid.clone();
+        hop += 1;
 
         // Wellformedness check of pointer from generator key
         let mut ctr: u32 = 0;
@@ -134,83 +416,150 @@ pub fn svr_trace(conn: &redis::Connection, m: &[u8], md: &TraceMetadata, uid: u3
             if ptr == bptr {
                 break true;
             };
-            let addr_filled: bool = conn.exists(&prf(&ptr, m)).unwrap();
-            if !addr_filled {
+            if !conn.exists(&prf(&ptr, m)) {
                 break false;
             };
             ctr = ctr + 1;
         };
         if !ptr_valid {
             info!(target: "root_traceback", "Malformed generator key usage");
-            return Tree {
-                uid: sid,
-                children: vec![svr_build_tree(conn, m, &fgk, rid)],
+            return TraceOutcome {
+                tree: TraceTree {
+                    uid: sid,
+                    edges: vec![TraceEdge {
+                        addr,
+                        ctr: None,
+                        wellformed: false,
+                        processed_at: ts,
+                        child: svr_build_tree(conn, m, &fgk, &bptr, &rid),
+                    }],
+                    tampered: false,
+                },
+                stop: StopReason::MalformedGeneratorKeyUsage,
+                hop,
+                origin_confirmed: false,
             };
         }
 
         // Next address
         bptr = decipher(&bptr, &ct_bptr);
-        addr = prf(&bptr, m);
+    };
+    TraceOutcome {
+        tree: svr_build_tree(conn, m, &root_gk, &bptr, &root),
+        stop,
+        hop,
+        origin_confirmed: stop == StopReason::ReachedOrigin,
     }
-    svr_build_tree(conn, m, &root_gk, root)
 }
 
-fn svr_build_tree(conn: &redis::Connection, m: &[u8], gk: &[u8; 16], uid: u32) -> Tree {
-    let mut tree = Tree {
-        uid: uid,
-        children: Vec::new(),
+// `bptr` is the pointer under which `uid` received the message, which each forward from `uid`
+// should carry along with its generator key `gk`.
+fn svr_build_tree(
+    conn: &impl Store,
+    m: &[u8],
+    gk: &[u8; 16],
+    bptr: &[u8; 16],
+    uid: &Participant,
+) -> TraceTree {
+    let mut tree = TraceTree {
+        uid: uid.clone(),
+        edges: Vec::new(),
+        tampered: false,
     };
     let mut ctr: u32 = 0;
     loop {
         let ptr = prf(gk, &ctr.to_be_bytes());
         let addr = prf(&ptr, m);
-        let addr_filled: bool = conn.exists(&addr).unwrap();
-        if !addr_filled {
-            break;
+        let State {
+            ct_bptr,
+            ct_gk,
+            ct_fgk,
+            ks_fgk: ks_platform,
+            sid,
+            rid,
+            ts,
+        } = match svr_read_state(conn, &addr) {
+            Ok(state) => state,
+            Err(stop) => {
+                tree.tampered = stop == StopReason::TamperedRecord;
+                break;
+            }
         };
-
-        let (_, _, ct_fgk, ks_platform, sid, rid) = svr_read_state(conn, &addr);
-        if sid != uid {
+        if !sid.same_user(uid) {
             break;
         }
 
+        let wellformed = decipher(&ptr, &ct_gk) == *gk && decipher(&ptr, &ct_bptr) == *bptr;
         let ks_sender = decipher(&ptr, &ct_fgk);
         let fgk = hash(&[&ks_sender[..], &ks_platform[..]].concat());
-        tree.children.push(svr_build_tree(conn, m, &fgk, rid));
+        tree.edges.push(TraceEdge {
+            addr,
+            ctr: Some(ctr),
+            wellformed,
+            processed_at: ts,
+            child: svr_build_tree(conn, m, &fgk, &ptr, &rid),
+        });
         ctr = ctr + 1;
     }
     tree
 }
 
-fn svr_read_state(
-    conn: &redis::Connection,
-    addr: &[u8; 16],
-) -> ([u8; 16], [u8; 16], [u8; 16], [u8; 16], u32, u32) {
-    let mut ct_bptr: [u8; 16] = Default::default();
-    let mut ct_gk: [u8; 16] = Default::default();
-    let mut ct_fgk: [u8; 16] = Default::default();
-    let mut ks_platform: [u8; 16] = Default::default();
-    let mut ctvec: Vec<u8>;
-
-    ctvec = conn.hget(addr, "ct_bptr").unwrap();
-    ct_bptr.copy_from_slice(&ctvec);
-    ctvec = conn.hget(addr, "ct_gk").unwrap();
-    ct_gk.copy_from_slice(&ctvec);
-    ctvec = conn.hget(addr, "ct_fgk").unwrap();
-    ct_fgk.copy_from_slice(&ctvec);
-    ctvec = conn.hget(addr, "ks_fgk").unwrap();
-    ks_platform.copy_from_slice(&ctvec);
-    let sid: u32 = conn.hget(addr, "sid").unwrap();
-    let rid: u32 = conn.hget(addr, "rid").unwrap();
-    (ct_bptr, ct_gk, ct_fgk, ks_platform, sid, rid)
+struct State {
+    ct_bptr: [u8; 16],
+    ct_gk: [u8; 16],
+    ct_fgk: [u8; 16],
+    ks_fgk: [u8; 16],
+    sid: Participant,
+    rid: Participant,
+    ts: Option<u64>,
+}
+
+fn svr_read_state(conn: &impl Store, addr: &[u8; 16]) -> Result<State, StopReason> {
+    let record = match conn.get_checked(addr) {
+        Ok(Some(record)) => record,
+        Ok(None) => return Err(StopReason::MissingRecord),
+        Err(Tampered) => return Err(StopReason::TamperedRecord),
+    };
+    let state = || {
+        Some(State {
+            ct_bptr: read_array(&record, "ct_bptr")?,
+            ct_gk: read_array(&record, "ct_gk")?,
+            ct_fgk: read_array(&record, "ct_fgk")?,
+            ks_fgk: read_array(&record, "ks_fgk")?,
+            sid: read_participant(&record, "sid")?,
+            rid: read_participant(&record, "rid")?,
+            ts: read_int(&record, "ts"),
+        })
+    };
+    state().ok_or(StopReason::MalformedRecord)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::analysis::cascade_stats;
+    use crate::store::MemoryStore;
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::collections::HashMap;
 
-    extern crate test;
-    use test::Bencher;
+    struct FixedClock(u64);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> u64 {
+            self.0
+        }
+    }
+
+    fn p(uid: u32) -> Participant {
+        Participant::from(uid)
+    }
+
+    fn ids(uids: &[u32]) -> Vec<Participant> {
+        uids.iter().map(|&uid| Participant::from(uid)).collect()
+    }
 
     fn init_logger() {
         //env_logger::init();
@@ -218,7 +567,7 @@ mod tests {
     }
 
     fn mock_send(
-        conn: &redis::Connection,
+        conn: &impl Store,
         m: &[u8],
         tmd: &TraceMetadata,
         ctr: u32,
@@ -227,7 +576,7 @@ mod tests {
     ) -> TraceMetadata {
         let k = rand::random::<[u8; 16]>();
         let tts = generate_tag(&k, m, &tmd, ctr);
-        let ttr = svr_process(conn, &tts, sid, rid).unwrap();
+        let ttr = svr_process(conn, &tts, &p(sid), &p(rid)).unwrap();
         verify_tag(&k, m, &ttr).unwrap()
     }
 
@@ -251,6 +600,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn reader_tag_matches() {
+        let m: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+        let k = rand::random::<[u8; 16]>();
+        let tmd0 = new_message(&m);
+        let tts = generate_tag_with_rng(&k, &m, &tmd0, 3, &mut StdRng::seed_from_u64(1));
+        let streamed = generate_tag_from_reader_with_rng(
+            &k,
+            &mut &m[..],
+            &tmd0,
+            3,
+            &mut StdRng::seed_from_u64(1),
+        )
+        .unwrap();
+        assert_eq!(
+            serde_json::to_string(&tts).unwrap(),
+            serde_json::to_string(&streamed).unwrap()
+        );
+    }
+
     #[test]
     fn process_tag_verifies() {
         let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
@@ -260,13 +629,78 @@ mod tests {
         let k = rand::random::<[u8; 16]>();
         let tmd0 = new_message(m.as_bytes());
         let tts = generate_tag(&k, m.as_bytes(), &tmd0, 0);
-        let ttr = svr_process(&conn, &tts, 0, 1).unwrap();
+        let ttr = svr_process(&conn, &tts, &p(0), &p(1)).unwrap();
         let tmd1 = verify_tag(&k, m.as_bytes(), &ttr).unwrap();
         assert_eq!(prf(&tmd0.gk, &0u32.to_be_bytes()), tmd1.bptr);
 
         let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
     }
 
+    #[test]
+    fn seeded_rng_reproduces_tags() {
+        let m = "Plaintext";
+        let k = [7u8; 16];
+        let mut rng1 = StdRng::seed_from_u64(0);
+        let mut rng2 = StdRng::seed_from_u64(0);
+
+        let tmd1 = new_message_with_rng(m.as_bytes(), &mut rng1);
+        let tmd2 = new_message_with_rng(m.as_bytes(), &mut rng2);
+        assert_eq!(tmd1.gk, tmd2.gk);
+
+        let tts1 = generate_tag_with_rng(&k, m.as_bytes(), &tmd1, 0, &mut rng1);
+        let tts2 = generate_tag_with_rng(&k, m.as_bytes(), &tmd2, 0, &mut rng2);
+        assert_eq!(tts1.addr, tts2.addr);
+        assert_eq!(tts1.ct_fgk, tts2.ct_fgk);
+    }
+
+    #[test]
+    fn seeded_rng_reproduces_platform_key() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let m = "Plaintext";
+        let k = rand::random::<[u8; 16]>();
+        let tmd0 = new_message(m.as_bytes());
+        let tts = generate_tag(&k, m.as_bytes(), &tmd0, 0);
+        let ttr1 =
+            svr_process_with_rng(&conn, &tts, &p(0), &p(1), &mut StdRng::seed_from_u64(1)).unwrap();
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+        let ttr2 =
+            svr_process_with_rng(&conn, &tts, &p(0), &p(1), &mut StdRng::seed_from_u64(1)).unwrap();
+        assert_eq!(ttr1.ks_fgk, ttr2.ks_fgk);
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn wellformed_tag_verifies() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let m = "Plaintext";
+        let tmd0 = new_message(m.as_bytes());
+
+        let k = rand::random::<[u8; 16]>();
+        let tts = generate_tag(&k, m.as_bytes(), &tmd0, 3);
+        let ttr = svr_process(&conn, &tts, &p(0), &p(1)).unwrap();
+        assert!(verify_tag_wellformed(&k, m.as_bytes(), &ttr).is_some());
+
+        // The sender delivers a forward generator key share other than the one the server stored
+        let k = rand::random::<[u8; 16]>();
+        let tts = generate_tag(&k, m.as_bytes(), &tmd0, 4);
+        let mut ttr_mal = svr_process(&conn, &tts, &p(0), &p(2)).unwrap();
+        let ptr = decipher(&k, &ttr_mal.ct_ptr);
+        ttr_mal.ct_fgk = encipher(&ptr, &rand::random::<[u8; 16]>());
+        let tmd2 = verify_tag(&k, m.as_bytes(), &ttr_mal).unwrap();
+        assert!(verify_tag_wellformed(&k, m.as_bytes(), &ttr_mal).is_none());
+        assert_eq!(
+            StopReason::MalformedForwardGeneratorKey,
+            svr_trace(&conn, m.as_bytes(), &tmd2, &p(2)).stop
+        );
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
     #[test]
     fn trace_simple_tree() {
         let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
@@ -277,19 +711,36 @@ mod tests {
         let tmd01 = mock_send(&conn, m.as_bytes(), &tmd0, 0, 0, 1);
         let tmd02 = mock_send(&conn, m.as_bytes(), &tmd0, 1, 0, 2);
 
-        let tree0 = svr_trace(&conn, m.as_bytes(), &tmd0, 0);
-        let tree1 = svr_trace(&conn, m.as_bytes(), &tmd01, 1);
-        let tree2 = svr_trace(&conn, m.as_bytes(), &tmd02, 2);
+        let trace0 = svr_trace(&conn, m.as_bytes(), &tmd0, &p(0));
+        let trace1 = svr_trace(&conn, m.as_bytes(), &tmd01, &p(1));
+        let trace2 = svr_trace(&conn, m.as_bytes(), &tmd02, &p(2));
+        assert_eq!(StopReason::ReachedOrigin, trace0.stop);
+        assert_eq!(0, trace0.hop);
+        assert_eq!(StopReason::ReachedOrigin, trace1.stop);
+        assert_eq!(1, trace1.hop);
+        assert!(trace2.origin_confirmed);
+        let edge = &trace0.tree.edges[1];
+        assert_eq!(Some(1), edge.ctr);
+        assert!(edge.wellformed);
+        assert_eq!(
+            prf(&prf(&tmd0.gk, &1u32.to_be_bytes()), m.as_bytes()),
+            edge.addr
+        );
+        let (tree0, tree1, tree2) = (
+            trace0.tree.to_tree(),
+            trace1.tree.to_tree(),
+            trace2.tree.to_tree(),
+        );
 
         let tree = Tree {
-            uid: 0,
+            uid: 0.into(),
             children: vec![
                 Tree {
-                    uid: 1,
+                    uid: 1.into(),
                     children: vec![],
                 },
                 Tree {
-                    uid: 2,
+                    uid: 2.into(),
                     children: vec![],
                 },
             ],
@@ -299,6 +750,60 @@ mod tests {
         assert_eq!(tree, tree1);
         assert_eq!(tree, tree2);
 
+        let stats = cascade_stats(&tree0);
+        assert_eq!(2, stats.recipients);
+        assert_eq!(1, stats.depth);
+        assert_eq!(2, stats.max_fanout);
+        assert_eq!(1, stats.forwarders);
+        assert_eq!(4.0 / 3.0, stats.structural_virality);
+        assert_eq!(vec![1, 2], stats.level_counts);
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn cascade_stats_mock_tree() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let m = "Plaintext";
+        let tmd = new_message(m.as_bytes());
+        mock_tree(&conn, m.as_bytes(), &tmd, 3, 2, 0);
+        let tree = svr_trace(&conn, m.as_bytes(), &tmd, &p(0)).tree.to_tree();
+
+        // Binary tree of 15 nodes: 2 subtrees of size 7, 4 of size 3 and 8 of size 1
+        let stats = cascade_stats(&tree);
+        assert_eq!(14, stats.recipients);
+        assert_eq!(3, stats.depth);
+        assert_eq!(2, stats.max_fanout);
+        assert_eq!(7, stats.forwarders);
+        assert_eq!(368.0 / 105.0, stats.structural_virality);
+        assert_eq!(vec![1, 2, 4, 8], stats.level_counts);
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn trace_processing_times() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let m = "Plaintext";
+        let tmd0 = new_message(m.as_bytes());
+        let mut rng = OsRng::new().unwrap();
+        for (ctr, ts) in [100, 200].iter().enumerate() {
+            let k = rand::random::<[u8; 16]>();
+            let tts = generate_tag(&k, m.as_bytes(), &tmd0, ctr as u32);
+            let rid = ctr as u32 + 1;
+            svr_process_with_clock(&conn, &tts, &p(0), &p(rid), &FixedClock(*ts), &mut rng)
+                .unwrap();
+        }
+
+        let trace = svr_trace(&conn, m.as_bytes(), &tmd0, &p(0));
+        let processed_at: Vec<Option<u64>> =
+            trace.tree.edges.iter().map(|e| e.processed_at).collect();
+        assert_eq!(vec![Some(100), Some(200)], processed_at);
+
         let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
     }
 
@@ -315,24 +820,29 @@ mod tests {
         let tmd12 = mock_send(&conn, m.as_bytes(), &tmd01, 0, 1, 2);
         let tmd13 = mock_send(&conn, m2.as_bytes(), &tmd01, 0, 1, 3);
 
-        let tree2 = svr_trace(&conn, m.as_bytes(), &tmd12, 2);
-        let tree3 = svr_trace(&conn, m2.as_bytes(), &tmd13, 3);
+        let trace2 = svr_trace(&conn, m.as_bytes(), &tmd12, &p(2));
+        let trace3 = svr_trace(&conn, m2.as_bytes(), &tmd13, &p(3));
+        assert_eq!(StopReason::ReachedOrigin, trace2.stop);
+        assert_eq!(StopReason::MissingRecord, trace3.stop);
+        assert_eq!(1, trace3.hop);
+        assert!(!trace3.origin_confirmed);
+        let (tree2, tree3) = (trace2.tree.to_tree(), trace3.tree.to_tree());
 
         let real_tree2 = Tree {
-            uid: 0,
+            uid: 0.into(),
             children: vec![Tree {
-                uid: 1,
+                uid: 1.into(),
                 children: vec![Tree {
-                    uid: 2,
+                    uid: 2.into(),
                     children: vec![],
                 }],
             }],
         };
 
         let real_tree3 = Tree {
-            uid: 1,
+            uid: 1.into(),
             children: vec![Tree {
-                uid: 3,
+                uid: 3.into(),
                 children: vec![],
             }],
         };
@@ -354,24 +864,29 @@ mod tests {
         let tmd12 = mock_send(&conn, m.as_bytes(), &tmd01, 0, 1, 2);
         let tmd13 = mock_send(&conn, m.as_bytes(), &tmd01, 2, 1, 3);
 
-        let tree2 = svr_trace(&conn, m.as_bytes(), &tmd12, 2);
-        let tree3 = svr_trace(&conn, m.as_bytes(), &tmd13, 3);
+        let trace2 = svr_trace(&conn, m.as_bytes(), &tmd12, &p(2));
+        let trace3 = svr_trace(&conn, m.as_bytes(), &tmd13, &p(3));
+        assert_eq!(StopReason::MalformedGeneratorKeyUsage, trace3.stop);
+        assert_eq!(1, trace3.hop);
+        assert_eq!(None, trace3.tree.edges[0].ctr);
+        assert!(!trace3.tree.edges[0].wellformed);
+        let (tree2, tree3) = (trace2.tree.to_tree(), trace3.tree.to_tree());
 
         let real_tree2 = Tree {
-            uid: 0,
+            uid: 0.into(),
             children: vec![Tree {
-                uid: 1,
+                uid: 1.into(),
                 children: vec![Tree {
-                    uid: 2,
+                    uid: 2.into(),
                     children: vec![],
                 }],
             }],
         };
 
         let real_tree3 = Tree {
-            uid: 1,
+            uid: 1.into(),
             children: vec![Tree {
-                uid: 3,
+                uid: 3.into(),
                 children: vec![],
             }],
         };
@@ -395,27 +910,35 @@ mod tests {
         let tmd12 = mock_send(&conn, m.as_bytes(), &tmd01_mal, 0, 1, 2);
         let tmd13 = mock_send(&conn, m.as_bytes(), &tmd01_mal, 1, 1, 3);
 
-        let tree0 = svr_trace(&conn, m.as_bytes(), &tmd0, 0);
-        let tree2 = svr_trace(&conn, m.as_bytes(), &tmd12, 2);
-        let tree3 = svr_trace(&conn, m.as_bytes(), &tmd13, 3);
+        let trace0 = svr_trace(&conn, m.as_bytes(), &tmd0, &p(0));
+        let trace2 = svr_trace(&conn, m.as_bytes(), &tmd12, &p(2));
+        let trace3 = svr_trace(&conn, m.as_bytes(), &tmd13, &p(3));
+        assert_eq!(StopReason::MalformedForwardGeneratorKey, trace2.stop);
+        assert_eq!(1, trace2.hop);
+        assert!(!trace2.origin_confirmed);
+        let (tree0, tree2, tree3) = (
+            trace0.tree.to_tree(),
+            trace2.tree.to_tree(),
+            trace3.tree.to_tree(),
+        );
 
         let real_tree0 = Tree {
-            uid: 0,
+            uid: 0.into(),
             children: vec![Tree {
-                uid: 1,
+                uid: 1.into(),
                 children: vec![],
             }],
         };
 
         let real_tree23 = Tree {
-            uid: 1,
+            uid: 1.into(),
             children: vec![
                 Tree {
-                    uid: 2,
+                    uid: 2.into(),
                     children: vec![],
                 },
                 Tree {
-                    uid: 3,
+                    uid: 3.into(),
                     children: vec![],
                 },
             ],
@@ -428,60 +951,354 @@ mod tests {
         let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
     }
 
-    #[bench]
-    fn bench_tag_gen(b: &mut Bencher) {
-        let m = [0u8; 256];
+    #[test]
+    fn trace_group_send() {
+        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
+        let conn = client.get_connection().unwrap();
+
+        let m = "Plaintext";
+        let tmd0 = new_message(m.as_bytes());
+        let tmd01 = mock_send(&conn, m.as_bytes(), &tmd0, 0, 0, 1);
+        let ks: Vec<[u8; 16]> = (0..3).map(|_| rand::random()).collect();
+        let tts = generate_group_tags(&ks, m.as_bytes(), &tmd0, 1);
+        let ttrs = svr_process_group(&conn, &tts, &p(0), &ids(&[2, 3, 4])).unwrap();
+        let tmds: Vec<TraceMetadata> = ks
+            .iter()
+            .zip(&ttrs)
+            .map(|(k, ttr)| verify_tag_wellformed(k, m.as_bytes(), ttr).unwrap())
+            .collect();
+
+        let tree = svr_trace(&conn, m.as_bytes(), &tmds[2], &p(4))
+            .tree
+            .to_tree();
+        let recipients: Vec<Participant> = tree.children.iter().map(|c| c.uid.clone()).collect();
+        assert_eq!(ids(&[1, 2, 3, 4]), recipients);
+        assert_eq!(
+            tree,
+            svr_trace(&conn, m.as_bytes(), &tmd01, &p(1)).tree.to_tree()
+        );
+
+        // Counter 2 is already used, so neither recipient of this group is stored
+        let tts = generate_group_tags(&ks[..2], m.as_bytes(), &tmd0, 4);
+        let mut tts_reused = generate_group_tags(&ks[..1], m.as_bytes(), &tmd0, 2);
+        tts_reused.insert(0, generate_tag(&ks[2], m.as_bytes(), &tmd0, 6));
+        assert!(svr_process_group(&conn, &tts_reused, &p(0), &ids(&[5, 6])).is_none());
+        assert!(!conn.exists(&tts_reused[0].addr));
+        assert!(svr_process_group(&conn, &tts, &p(0), &ids(&[5])).is_none());
+        assert!(svr_process_group(&conn, &tts, &p(0), &ids(&[5, 6])).is_some());
+        assert!(svr_process_group(&conn, &[], &p(0), &[]).is_none());
+
+        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    }
+
+    #[test]
+    fn trace_device_hop() {
+        let store = MemoryStore::new();
+        let m = b"Plaintext";
+        let (phone, laptop) = (
+            Participant::new(1, b"phone"),
+            Participant::new(1, b"laptop"),
+        );
+
+        // User 1 receives on their phone and forwards from two other devices
+        let tmd0 = new_message(m);
         let k = rand::random::<[u8; 16]>();
-        let md = TraceMetadata {
-            bptr: rand::random::<[u8; 16]>(),
+        let tts = generate_tag(&k, m, &tmd0, 0);
+        let tmd1 = verify_tag(&k, m, &svr_process(&store, &tts, &p(0), &phone).unwrap()).unwrap();
+        let tmd2 = mock_send(&store, m, &tmd1, 0, 1, 2);
+        let k = rand::random::<[u8; 16]>();
+        let tts = generate_tag(&k, m, &tmd1, 1);
+        svr_process(&store, &tts, &laptop, &p(3)).unwrap();
+
+        let trace = svr_trace(&store, m, &tmd2, &p(2));
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+        let tree = trace.tree.to_tree();
+        assert_eq!(
+            vec![phone],
+            tree.children
+                .iter()
+                .map(|c| c.uid.clone())
+                .collect::<Vec<_>>()
+        );
+        let recipients: Vec<Participant> = tree.children[0]
+            .children
+            .iter()
+            .map(|c| c.uid.clone())
+            .collect();
+        assert_eq!(ids(&[2, 3]), recipients);
+    }
+
+    #[test]
+    fn trace_batch() {
+        let store = MemoryStore::new();
+        let m = "Plaintext";
+        let tmd0 = new_message(m.as_bytes());
+        let ks: Vec<[u8; 16]> = (0..3).map(|_| rand::random()).collect();
+        let items: Vec<(SenderTraceTag, Participant, Participant)> = [0, 1, 1]
+            .iter()
+            .zip(&ks)
+            .enumerate()
+            .map(|(i, (&ctr, k))| {
+                let tts = generate_tag(k, m.as_bytes(), &tmd0, ctr);
+                (tts, p(0), p(i as u32 + 1))
+            })
+            .collect();
+        let mut rng = StdRng::seed_from_u64(1);
+        let ttrs = svr_process_batch_with_clock(&store, &items, &FixedClock(500), &mut rng);
+        assert!(ttrs[2].is_none());
+        assert_eq!(2, store.len());
+
+        let tmd02 = verify_tag(&ks[1], m.as_bytes(), ttrs[1].as_ref().unwrap()).unwrap();
+        let trace = svr_trace(&store, m.as_bytes(), &tmd02, &p(2));
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+        let recipients: Vec<Participant> = trace
+            .tree
+            .edges
+            .iter()
+            .map(|e| e.child.uid.clone())
+            .collect();
+        assert_eq!(ids(&[1, 2]), recipients);
+        assert_eq!(Some(500), trace.tree.edges[0].processed_at);
+    }
+
+    #[test]
+    fn trace_malformed_record() {
+        let store = MemoryStore::new();
+        let m = "Plaintext";
+        let tmd0 = new_message(m.as_bytes());
+        let tmd01 = mock_send(&store, m.as_bytes(), &tmd0, 0, 0, 1);
+
+        // Record without a platform key share for a forward from 1 to 2
+        let ptr = prf(&tmd01.gk, &0u32.to_be_bytes());
+        store.insert(
+            &prf(&ptr, m.as_bytes()),
+            &[
+                ("ct_bptr", &encipher(&ptr, &tmd01.bptr)),
+                ("ct_gk", &encipher(&ptr, &tmd01.gk)),
+                ("ct_fgk", &[0; 16]),
+                ("sid", b"1"),
+                ("rid", b"2"),
+            ],
+        );
+        let tmd12 = TraceMetadata {
+            bptr: ptr,
             gk: rand::random::<[u8; 16]>(),
         };
-        b.iter(|| generate_tag(&k, &m, &md, 0));
+
+        let trace = svr_trace(&store, m.as_bytes(), &tmd12, &p(2));
+        assert_eq!(StopReason::MalformedRecord, trace.stop);
+        assert_eq!(0, trace.hop);
+        let trace = svr_trace(&store, m.as_bytes(), &tmd01, &p(1));
+        assert_eq!(StopReason::ReachedOrigin, trace.stop);
+        assert!(trace.tree.edges[0].child.edges.is_empty());
     }
 
-    #[bench]
-    fn bench_tag_receive(b: &mut Bencher) {
-        let m = [0u8; 256];
-        let k = rand::random::<[u8; 16]>();
-        let ptr = rand::random::<[u8; 16]>();
-        let ttr = RecTraceTag {
-            addr: prf(&k, &m),
-            ct_ptr: encipher(&k, &ptr),
-            ct_fgk: rand::random::<[u8; 16]>(),
-            ks_fgk: rand::random::<[u8; 16]>(),
+    #[derive(Clone, Debug)]
+    enum Fault {
+        Honest,
+        Switch,
+        SkipCounters(u32),
+        ForgedSender,
+    }
+
+    fn forwards() -> impl Strategy<Value = Vec<(Index, u32, Fault)>> {
+        let fault = prop_oneof![
+            6 => Just(Fault::Honest),
+            1 => Just(Fault::Switch),
+            1 => (1..3u32).prop_map(Fault::SkipCounters),
+            1 => Just(Fault::ForgedSender),
+        ];
+        // Few distinct users, so the same user receives the message along several paths
+        prop::collection::vec((any::<Index>(), 0..6u32, fault), 0..16)
+    }
+
+    // Ground truth for a cascade: node 0 originates the message and node `i > 0` received
+    // `msg[i]` from `parent[i]`, recorded with sender id `sid[i]` and counter `ctr[i]`. A tampered
+    // node forwards with a generator key other than the one the platform gave it.
+    struct Cascade {
+        uid: Vec<u32>,
+        parent: Vec<usize>,
+        sid: Vec<u32>,
+        ctr: Vec<u32>,
+        msg: Vec<Vec<u8>>,
+        tampered: Vec<bool>,
+        md: Vec<TraceMetadata>,
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Gk {
+        Forward(usize),
+        Tampered(usize),
+    }
+
+    fn mock_cascade(
+        conn: &impl Store,
+        forwards: &[(Index, u32, Fault)],
+        tampered: &[bool],
+    ) -> Cascade {
+        let m = b"Plaintext".to_vec();
+        let mut c = Cascade {
+            uid: vec![0],
+            parent: vec![0],
+            sid: vec![0],
+            ctr: vec![0],
+            tampered: vec![tampered[0]],
+            md: vec![new_message(&m)],
+            msg: vec![m],
         };
-        b.iter(|| verify_tag(&k, &m, &ttr));
+        let tampered_gk: Vec<[u8; 16]> = tampered.iter().map(|_| rand::random()).collect();
+        let mut next_ctr: HashMap<(usize, Vec<u8>), u32> = HashMap::new();
+        for (i, (parent, uid, fault)) in forwards.iter().enumerate() {
+            let i = i + 1;
+            let p = parent.index(i);
+            let msg = match fault {
+                Fault::Switch => format!("Switch {}", i).into_bytes(),
+                _ => c.msg[p].clone(),
+            };
+            let next = next_ctr.entry((p, msg.clone())).or_insert(0);
+            if let Fault::SkipCounters(skip) = fault {
+                *next += skip;
+            }
+            let ctr = *next;
+            *next += 1;
+            let sid = match fault {
+                Fault::ForgedSender => 1000 + i as u32,
+                _ => c.uid[p],
+            };
+            let mut md = c.md[p].clone();
+            if c.tampered[p] {
+                md.gk = tampered_gk[p];
+            }
+
+            c.md.push(mock_send(conn, &msg, &md, ctr, sid, *uid));
+            c.uid.push(*uid);
+            c.parent.push(p);
+            c.sid.push(sid);
+            c.ctr.push(ctr);
+            c.msg.push(msg);
+            c.tampered.push(tampered[i]);
+        }
+        c
     }
 
-    #[bench]
-    fn bench_tag_process(b: &mut Bencher) {
-        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-        let conn = client.get_connection().unwrap();
-        let sid = rand::random::<u32>();
-        let rid = rand::random::<u32>();
-        let tts = SenderTraceTag {
-            addr: rand::random::<[u8; 16]>(),
-            ct_ptr: rand::random::<[u8; 16]>(),
-            ct_bptr: rand::random::<[u8; 16]>(),
-            ct_gk: rand::random::<[u8; 16]>(),
-            ct_fgk: rand::random::<[u8; 16]>(),
+    fn forward_with(c: &Cascade, p: usize, m: &[u8], ctr: u32) -> Option<usize> {
+        (1..c.uid.len()).find(|&y| c.parent[y] == p && c.msg[y] == m && c.ctr[y] == ctr)
+    }
+
+    // The tree `svr_build_tree` should find below `node` for user `uid`, walking `node`'s own
+    // generator key or, if `tampered`, the one it actually forwarded with.
+    fn expected_tree(c: &Cascade, node: usize, uid: u32, m: &[u8], tampered: bool) -> Tree {
+        let mut children = Vec::new();
+        if tampered == c.tampered[node] {
+            for ctr in 0.. {
+                match forward_with(c, node, m, ctr) {
+                    Some(y) if c.sid[y] == uid => {
+                        children.push(expected_tree(c, y, c.uid[y], m, false))
+                    }
+                    _ => break,
+                }
+            }
+        }
+        Tree {
+            uid: uid.into(),
+            children,
+        }
+    }
+
+    // Mirrors the checks `svr_trace` makes on each stored record using the cascade's ground truth.
+    fn expected_trace(c: &Cascade, v: usize) -> (Tree, StopReason, usize) {
+        let m = &c.msg[v];
+        let (mut x, mut gk, mut prev_sid, mut hop) = (v, Gk::Forward(v), c.uid[v], 0);
+        let mut root = (v, c.uid[v], false);
+        let stop = loop {
+            if x == 0 {
+                break StopReason::ReachedOrigin;
+            }
+            if c.msg[x] != *m {
+                break StopReason::MissingRecord;
+            }
+            if prev_sid != c.uid[x] {
+                break StopReason::IdentityMismatch;
+            }
+            if gk != Gk::Forward(x) {
+                break StopReason::MalformedForwardGeneratorKey;
+            }
+
+            let p = c.parent[x];
+            gk = if c.tampered[p] {
+                Gk::Tampered(p)
+            } else {
+                Gk::Forward(p)
+            };
+            root = (p, c.sid[x], c.tampered[p]);
+            prev_sid = c.sid[x];
+            hop += 1;
+            if (0..c.ctr[x]).any(|ctr| forward_with(c, p, m, ctr).is_none()) {
+                let child = expected_tree(c, x, c.uid[x], m, false);
+                let tree = Tree {
+                    uid: c.sid[x].into(),
+                    children: vec![child],
+                };
+                return (tree, StopReason::MalformedGeneratorKeyUsage, hop);
+            }
+            x = p;
         };
-        b.iter(|| {
-            svr_process(&conn, &tts, sid, rid);
-            let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
-        });
+        (expected_tree(c, root.0, root.1, m, root.2), stop, hop)
     }
 
-    #[bench]
-    fn bench_trace_tree(b: &mut Bencher) {
-        let depth = 4;
-        let span = 3;
-        let client = redis::Client::open("redis://127.0.0.1:6379/").unwrap();
-        let conn = client.get_connection().unwrap();
-        let m = [0u8; 256];
-        let tmd = new_message(&m);
-        mock_tree(&conn, &m, &tmd, depth, span, 0);
-        b.iter(|| svr_trace(&conn, &m, &tmd, 0));
-        let _: () = redis::cmd("FLUSHDB").query(&conn).unwrap();
+    // Tracing every node of every generated cascade is slow in debug builds
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn honest_cascade_traces_agree(
+            forwards in prop::collection::vec((any::<Index>(), 0..6u32), 0..16)
+        ) {
+            let store = MemoryStore::new();
+            let forwards: Vec<_> =
+                forwards.into_iter().map(|(p, uid)| (p, uid, Fault::Honest)).collect();
+            let c = mock_cascade(&store, &forwards, &[false; 17]);
+            let full = expected_tree(&c, 0, 0, &c.msg[0], false);
+
+            for v in 0..c.uid.len() {
+                let trace = svr_trace(&store, &c.msg[v], &c.md[v], &p(c.uid[v]));
+                let mut depth = 0;
+                let mut x = v;
+                while x != 0 {
+                    x = c.parent[x];
+                    depth += 1;
+                }
+                prop_assert_eq!(StopReason::ReachedOrigin, trace.stop);
+                prop_assert!(trace.origin_confirmed);
+                prop_assert_eq!(depth, trace.hop);
+                prop_assert_eq!(&full, &trace.tree.to_tree());
+            }
+        }
+
+        #[test]
+        fn trace_matches_cascade_model(
+            forwards in forwards(),
+            tampered in prop::collection::vec(prop::bool::weighted(0.1), 17),
+            bptr in any::<[u8; 16]>(),
+            gk in any::<[u8; 16]>(),
+        ) {
+            let store = MemoryStore::new();
+            let c = mock_cascade(&store, &forwards, &tampered);
+
+            for v in 0..c.uid.len() {
+                let trace = svr_trace(&store, &c.msg[v], &c.md[v], &p(c.uid[v]));
+                let (tree, stop, hop) = expected_trace(&c, v);
+                prop_assert_eq!(stop, trace.stop);
+                prop_assert_eq!(hop, trace.hop);
+                prop_assert_eq!(stop == StopReason::ReachedOrigin, trace.origin_confirmed);
+                prop_assert_eq!(tree, trace.tree.to_tree());
+            }
+
+            // Metadata not issued by the platform points at no record
+            let trace = svr_trace(&store, &c.msg[0], &TraceMetadata { bptr, gk }, &p(7));
+            prop_assert_eq!(StopReason::MissingRecord, trace.stop);
+            prop_assert_eq!(0, trace.hop);
+            prop_assert!(trace.tree.edges.is_empty());
+        }
     }
 }